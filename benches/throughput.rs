@@ -0,0 +1,179 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use gen_id::{
+    AtomicIdGenerator, Clock, ConfigPreset, ConstIdGenerator, IdGenerator, LocalIdGenerator,
+    DEFAULT_EPOCH,
+};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+const IDS_PER_THREAD: u64 = 10_000;
+
+// Moves on a millisecond every 256 reads so the benchmark measures the cost of
+// taking a sequence number rather than the 1024 ids/ms ceiling.
+struct TickingClock(AtomicU64);
+
+impl Clock for TickingClock {
+    fn now_millis(&self) -> u64 {
+        DEFAULT_EPOCH + self.0.fetch_add(1, Ordering::Relaxed) / 256
+    }
+}
+
+fn generate_concurrently(gen: &Arc<IdGenerator>, threads: u16) {
+    let handles: Vec<_> = (0..threads)
+        .map(|node_id| {
+            let gen = gen.clone();
+            let node_id = gen.node_id(node_id as u64).unwrap();
+            thread::spawn(move || {
+                for _ in 0..IDS_PER_THREAD {
+                    black_box(gen.next_id(node_id));
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+fn contention(c: &mut Criterion) {
+    let mut group = c.benchmark_group("next_id_contention");
+    for threads in [1u16, 2, 4, 8] {
+        group.throughput(Throughput::Elements(IDS_PER_THREAD * threads as u64));
+
+        let gen = Arc::new(IdGenerator::with_clock(
+            ConfigPreset::ShortEpochMaxNodes,
+            DEFAULT_EPOCH,
+            TickingClock(AtomicU64::new(0)),
+        ));
+        group.bench_with_input(BenchmarkId::from_parameter(threads), &threads, |b, &n| {
+            b.iter(|| generate_concurrently(&gen, n))
+        });
+    }
+    group.finish();
+}
+
+fn atomic_concurrently(gen: &Arc<AtomicIdGenerator>, threads: u16) {
+    let handles: Vec<_> = (0..threads)
+        .map(|node_id| {
+            let gen = gen.clone();
+            let node_id = gen.node_id(node_id as u64).unwrap();
+            thread::spawn(move || {
+                for _ in 0..IDS_PER_THREAD {
+                    black_box(gen.next_id(node_id));
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+fn ordering_contention(c: &mut Criterion) {
+    // The lock-free generator's swap with each ordering, against the lock
+    let mut group = c.benchmark_group("ordering_contention");
+    for threads in [1u16, 2, 4, 8] {
+        group.throughput(Throughput::Elements(IDS_PER_THREAD * threads as u64));
+
+        for (name, ordering) in [
+            ("relaxed", Ordering::Relaxed),
+            ("seq_cst", Ordering::SeqCst),
+        ] {
+            let gen = Arc::new(
+                AtomicIdGenerator::with_clock(
+                    ConfigPreset::ShortEpochMaxNodes,
+                    DEFAULT_EPOCH,
+                    TickingClock(AtomicU64::new(0)),
+                )
+                .with_ordering(ordering),
+            );
+            group.bench_with_input(BenchmarkId::new(name, threads), &threads, |b, &n| {
+                b.iter(|| atomic_concurrently(&gen, n))
+            });
+        }
+
+        let gen = Arc::new(IdGenerator::with_clock(
+            ConfigPreset::ShortEpochMaxNodes,
+            DEFAULT_EPOCH,
+            TickingClock(AtomicU64::new(0)),
+        ));
+        group.bench_with_input(BenchmarkId::new("mutex", threads), &threads, |b, &n| {
+            b.iter(|| generate_concurrently(&gen, n))
+        });
+    }
+    group.finish();
+}
+
+fn sequence_ceiling(c: &mut Criterion) {
+    // With the real clock a single stream tops out at 1024 ids per millisecond
+    let gen = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
+    let node = gen.node_id(1).unwrap();
+    c.bench_function("next_id_system_clock", |b| {
+        b.iter(|| black_box(gen.next_id(node)))
+    });
+}
+
+fn const_layout(c: &mut Criterion) {
+    // The same layout with its shifts fixed at compile time and read at run time
+    let mut group = c.benchmark_group("const_vs_dynamic");
+    let fixed = ConstIdGenerator::<41, 10, 0>::with_clock(
+        DEFAULT_EPOCH,
+        2,
+        TickingClock(AtomicU64::new(0)),
+    )
+    .unwrap();
+    let dynamic = IdGenerator::with_clock(
+        ConfigPreset::Custom(DEFAULT_EPOCH, 41, 10, 0, 10, 2),
+        DEFAULT_EPOCH,
+        TickingClock(AtomicU64::new(0)),
+    );
+    let node = dynamic.node_id(1).unwrap();
+    group.bench_function("const_next_id", |b| {
+        b.iter(|| black_box(fixed.next_id(node)))
+    });
+    group.bench_function("dynamic_next_id", |b| {
+        b.iter(|| black_box(dynamic.next_id(node)))
+    });
+    let id = dynamic.next_id(node);
+    group.bench_function("const_decode_id", |b| {
+        b.iter(|| black_box(fixed.decode_id(black_box(id))))
+    });
+    group.bench_function("dynamic_decode_id", |b| {
+        b.iter(|| black_box(dynamic.decode_id(black_box(id))))
+    });
+    group.finish();
+}
+
+fn local_generator(c: &mut Criterion) {
+    // An uncontended lock against no lock at all
+    let mut group = c.benchmark_group("local_vs_shared");
+    let local = LocalIdGenerator::with_clock(
+        ConfigPreset::ShortEpochMaxNodes,
+        DEFAULT_EPOCH,
+        TickingClock(AtomicU64::new(0)),
+    );
+    let shared = IdGenerator::with_clock(
+        ConfigPreset::ShortEpochMaxNodes,
+        DEFAULT_EPOCH,
+        TickingClock(AtomicU64::new(0)),
+    );
+    let node = shared.node_id(1).unwrap();
+    group.bench_function("local_next_id", |b| {
+        b.iter(|| black_box(local.next_id(node)))
+    });
+    group.bench_function("shared_next_id", |b| {
+        b.iter(|| black_box(shared.next_id(node)))
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    contention,
+    ordering_contention,
+    sequence_ceiling,
+    const_layout,
+    local_generator
+);
+criterion_main!(benches);
@@ -0,0 +1,45 @@
+#![no_main]
+
+use gen_id::{ConfigPreset, IdConfig, IdGenerator, DEFAULT_EPOCH};
+use libfuzzer_sys::fuzz_target;
+
+// The first 8 bytes are the id. Any 6 bytes after it are field widths for an
+// unchecked layout, which may not fit in 64 bits at all.
+fuzz_target!(|data: &[u8]| {
+    let Some((id, rest)) = data.split_first_chunk::<8>() else {
+        return;
+    };
+    let id = u64::from_le_bytes(*id);
+
+    for preset in [
+        ConfigPreset::ShortEpochMaxNodes,
+        ConfigPreset::ShardedConfig,
+        ConfigPreset::ShardedConfigWide,
+        ConfigPreset::Snowflake,
+    ] {
+        let gen = IdGenerator::new(preset, DEFAULT_EPOCH);
+        let decoded = gen.decode_id(id);
+        assert!(decoded.node_id < gen.max_node_count());
+        assert!(decoded.time <= gen.max_timestamp_millis());
+        let _ = gen.try_decode_id(id);
+    }
+
+    if let Some(widths) = rest.first_chunk::<6>() {
+        let [epoch_bits, node_bits, shard_bits, inc_bits, config_bits, shard_checksum_bits] =
+            *widths;
+        let config = IdConfig {
+            epoch: DEFAULT_EPOCH,
+            epoch_bits,
+            node_bits,
+            shard_bits,
+            config_id: 0,
+            config_bits,
+            inc_bits,
+            shard_checksum_bits,
+            shard2_bits: 0,
+        };
+        let _ = config.decode(id);
+        let _ = config.verify_shard_checksum(id);
+        let _ = config.validate();
+    }
+});
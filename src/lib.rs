@@ -1,306 +1,6756 @@
-use serde::Serialize;
-use std::sync::atomic::{AtomicU16, Ordering};
-use std::time::{SystemTime, UNIX_EPOCH};
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+use core::fmt;
+use core::str::FromStr;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "std")]
+use std::cell::Cell;
+#[cfg(feature = "std")]
+use std::hint;
+#[cfg(feature = "std")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+#[cfg(feature = "std")]
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[cfg(target_arch = "wasm32")]
 use wasm_bindgen::prelude::*;
 
 pub const DEFAULT_EPOCH: u64 = 1609459200000;
 
+// Widths of the fixed fields at the bottom of every id
+const CONFIG_BITS: u8 = 3;
+const INC_BITS: u8 = 10;
+const MAX_INC_BITS: u8 = 16;
+#[cfg(feature = "std")]
+const DEFAULT_EXHAUSTION_THRESHOLD: f64 = 0.9;
+
+#[cfg(feature = "std")]
+const U128_TIME_MASK: u128 = (1 << 48) - 1;
+#[cfg(feature = "std")]
+const U128_VERSION: u128 = 0b1000;
+#[cfg(feature = "std")]
+const U128_VARIANT: u128 = 0b10;
+#[cfg(feature = "std")]
+const U128_RANDOM_MASK: u128 = (1 << 30) - 1;
+
 #[repr(u8)]
 pub enum ConfigPreset {
     ShortEpochMaxNodes = 0,
     ShardedConfig = 1,
-    Custom(u64, u8, u8, u8, u8),
+    // (epoch, epoch_bits, node_bits, shard_bits, inc_bits, config_id)
+    Custom(u64, u8, u8, u8, u8, u8),
+    // The Twitter/Discord layout: 41 time | 10 node | 12 sequence, with no
+    // config field
+    Snowflake,
+    // Sharding with a 41-bit time field (~69 years) instead of 32 bits
+    // (~49 days), paid for with node bits: 41 time | 5 node | 5 shard
+    ShardedConfigWide,
+}
+
+impl ConfigPreset {
+    // (epoch_bits, node_bits, shard_bits)
+    fn field_bits(&self) -> (u8, u8, u8) {
+        match *self {
+            ConfigPreset::ShortEpochMaxNodes => (37, 14, 0),
+            ConfigPreset::ShardedConfig => (32, 14, 5), // upto 32 shards
+            ConfigPreset::Snowflake => (41, 10, 0),
+            ConfigPreset::ShardedConfigWide => (41, 5, 5),
+            ConfigPreset::Custom(_, epoch_bits, node_bits, shard_bits, _, _) => {
+                (epoch_bits, node_bits, shard_bits)
+            }
+        }
+    }
+
+    fn config_id(&self) -> u8 {
+        match *self {
+            ConfigPreset::ShortEpochMaxNodes => 3,
+            ConfigPreset::ShardedConfig => 1,
+            ConfigPreset::Custom(_, _, _, _, _, config_id) => config_id,
+            ConfigPreset::Snowflake => 0,
+            ConfigPreset::ShardedConfigWide => 4,
+        }
+    }
+
+    // (config_bits, inc_bits)
+    fn fixed_bits(&self) -> (u8, u8) {
+        match *self {
+            ConfigPreset::Custom(_, _, _, _, inc_bits, _) => (CONFIG_BITS, inc_bits),
+            ConfigPreset::Snowflake => (0, 12),
+            _ => (CONFIG_BITS, INC_BITS),
+        }
+    }
+}
+
+pub trait Clock: Send + Sync {
+    /// Milliseconds since the UNIX epoch.
+    fn now_millis(&self) -> u64;
+}
+
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+#[cfg(feature = "std")]
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        // A clock set before 1970 reads as 0, which is behind any epoch
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_millis() as u64)
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now_millis(&self) -> u64 {
+        (**self).now_millis()
+    }
+}
+
+/// What `next_id` does while it waits for the clock after a millisecond's
+/// sequence is spent.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RolloverStrategy {
+    /// Busy-polls the clock, for the lowest latency.
+    #[default]
+    Spin,
+    /// Yields the thread to the scheduler between polls.
+    YieldNow,
+    /// Sleeps for a millisecond between polls, leaving the CPU idle.
+    SleepUntilNextMs,
+}
+
+#[cfg(feature = "std")]
+impl RolloverStrategy {
+    fn wait(self) {
+        match self {
+            RolloverStrategy::Spin => hint::spin_loop(),
+            RolloverStrategy::YieldNow => std::thread::yield_now(),
+            RolloverStrategy::SleepUntilNextMs => std::thread::sleep(Duration::from_millis(1)),
+        }
+    }
+}
+
+// The clock behind `IdGenerator::deterministic`, which always reads the
+// millisecond its ids start at. Later ones are worked out from the sequence,
+// so reading it never changes what the generator issues.
+#[cfg(feature = "std")]
+struct FixedClock(u64);
+
+#[cfg(feature = "std")]
+impl Clock for FixedClock {
+    fn now_millis(&self) -> u64 {
+        self.0
+    }
+}
+
+// Hooks for counting what a generator does, e.g. into Prometheus counters.
+// Both default to doing nothing. They are called on the generating thread, so
+// they should be cheap.
+#[cfg(feature = "std")]
+pub trait Metrics: Send + Sync {
+    /// Called once for every id handed out.
+    fn on_id_generated(&self) {}
+
+    /// Called when a millisecond's sequence ran out and the generator had to
+    /// wait for the clock to move on, with how long that took by the
+    /// generator's clock.
+    fn on_rollover_wait(&self, _waited: Duration) {}
+}
+
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopMetrics;
+
+#[cfg(feature = "std")]
+impl Metrics for NoopMetrics {}
+
+#[cfg(feature = "std")]
+impl<M: Metrics + ?Sized> Metrics for Arc<M> {
+    fn on_id_generated(&self) {
+        (**self).on_id_generated()
+    }
+
+    fn on_rollover_wait(&self, waited: Duration) {
+        (**self).on_rollover_wait(waited)
+    }
 }
 
+/// The bit layout of a generator, without any of its counter state. Persist
+/// this to make sure every deployment encodes and decodes ids the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdConfig {
+    pub epoch: u64,
+    pub epoch_bits: u8,
+    pub node_bits: u8,
+    pub shard_bits: u8,
+    pub config_id: u8,
+    // Configs persisted before these were added always used 3 and 10
+    #[serde(default = "default_config_bits")]
+    pub config_bits: u8,
+    #[serde(default = "default_inc_bits")]
+    pub inc_bits: u8,
+    // Bits above the time field holding a checksum of the shard, 0 to leave
+    // them out. Left out of the serialized form when 0, so it stays readable
+    // by older versions.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub shard_checksum_bits: u8,
+    // How many of the shard bits, from the bottom, hold a secondary shard
+    // under the primary one in the rest; 0 for a single shard level
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub shard2_bits: u8,
+    // How many of the node bits, from the top, hold a generator's random salt
+    // instead of the node id; 0 for none
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub salt_bits: u8,
+}
+
+#[cfg(feature = "std")]
+/// Generates ids for one bit layout. A generator can be shared between
+/// threads behind an `Arc`: every call to `next_id` takes its sequence number
+/// and millisecond from the same locked counter state, so no two calls ever
+/// return the same id, whichever threads they come from.
 pub struct IdGenerator {
     epoch: u64,
     epoch_bits: u8,
     node_bits: u8,
     shard_bits: u8,
-    max_nodes: u16,
+    shard_checksum_bits: u8,
+    shard2_bits: u8,
+    max_nodes: u32,
     config_id: u8,
-    next_id: AtomicU16,
+    config_bits: u8,
+    inc_bits: u8,
+    sequences: Sequences,
+    clock: Arc<dyn Clock>,
+    metrics: Arc<dyn Metrics>,
+    // How far the clock may fall behind the last issued millisecond before
+    // ids stop reusing that millisecond and fail instead
+    monotonic_tolerance: Option<u64>,
+    // The node `next` issues for, 0 unless set with `with_fixed_node`
+    fixed_node: NodeId,
+    // The `exhaustion_ratio` past which `try_next_id_guarded` refuses ids
+    exhaustion_threshold: f64,
+    rollover_strategy: RolloverStrategy,
+    reset_sequence_each_ms: bool,
+    // Set by `deterministic`: time comes from the sequence, starting where
+    // the fixed clock reads
+    deterministic: bool,
+    // Called once for each millisecond whose sequence runs out with ids
+    // still wanted
+    on_exhaustion: Option<Arc<dyn Fn() + Send + Sync>>,
+    // Stands in for the rollover strategy's wait, so tests can count the
+    // waits and move a mock clock on instead of timing real ones
+    #[cfg(test)]
+    rollover_wait_hook: Option<Arc<dyn Fn(RolloverStrategy) + Send + Sync>>,
+    // A random value in the top `salt_bits` of the node field, drawn once per
+    // generator so processes reusing a node id still issue distinct ids
+    salt_bits: u8,
+    salt: u64,
+    #[cfg(feature = "secure")]
+    random_window: Mutex<RandomWindow>,
+    // Sequence values `next_id_at` has used in each node's milliseconds from
+    // before live ids started
+    backfill: Mutex<std::collections::HashMap<(u32, u64), u64>>,
 }
 
-#[derive(Debug, Serialize)]
-pub struct DecodedId {
-    pub time: u64,
-    pub node_id: u64,
-    pub shard_id: u16,
-    pub incrementing_id: u64,
-    pub config_id: u8,
+#[cfg(feature = "std")]
+const _: () = {
+    fn _assert_sync<T: Sync + Send>() {}
+    fn _assert_generator() {
+        _assert_sync::<IdGenerator>();
+    }
+};
+
+// Shows the layout and where each field sits, plus the counter value the
+// next id takes; the clock and metrics are left out
+#[cfg(feature = "std")]
+impl fmt::Debug for IdGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let config = IdConfig::from(self);
+        f.debug_struct("IdGenerator")
+            .field("epoch", &self.epoch)
+            .field("epoch_bits", &self.epoch_bits)
+            .field("node_bits", &self.node_bits)
+            .field("shard_bits", &self.shard_bits)
+            .field("inc_bits", &self.inc_bits)
+            .field("config_bits", &self.config_bits)
+            .field("config_id", &self.config_id)
+            .field("shard_checksum_bits", &self.shard_checksum_bits)
+            .field("shard2_bits", &self.shard2_bits)
+            .field("salt_bits", &self.salt_bits)
+            .field("inc_shift", &config.inc_shift())
+            .field("shard_shift", &config.shard_shift())
+            .field("node_shift", &config.node_shift())
+            .field("time_shift", &config.time_shift())
+            .field("max_nodes", &self.max_nodes)
+            .field(
+                "per_node_sequences",
+                &matches!(self.sequences, Sequences::PerNode(_)),
+            )
+            .field("current_sequence", &self.current_sequence())
+            .field("monotonic_tolerance", &self.monotonic_tolerance)
+            .field("rollover_strategy", &self.rollover_strategy)
+            .field("reset_sequence_each_ms", &self.reset_sequence_each_ms)
+            .finish()
+    }
 }
 
-impl IdGenerator {
-    pub fn new(preset: ConfigPreset, epoch: u64) -> Self {
-        match preset {
-            ConfigPreset::ShortEpochMaxNodes => Self {
-                epoch,
-                epoch_bits: 37,
-                node_bits: 14,
-                shard_bits: 0,
-                max_nodes: 16384,
-                config_id: 3,
-                next_id: AtomicU16::new(0),
-            },
-            ConfigPreset::ShardedConfig => Self {
-                epoch,
-                epoch_bits: 32,
-                node_bits: 14,
-                shard_bits: 5, // upto 32 shards
-                max_nodes: 16384,
-                config_id: 1,
-                next_id: AtomicU16::new(0),
-            },
-            ConfigPreset::Custom(epoch, epoch_bits, node_bits, shard_bits, config_id) => Self {
-                epoch,
-                epoch_bits,
-                node_bits,
-                shard_bits,
-                max_nodes: (1 << node_bits) as u16,
-                config_id,
-                next_id: AtomicU16::new(0),
+// A clone has the same layout, clock, metrics and salt, but starts its own
+// counter from 0; it doesn't share the original's. Two generators issuing for
+// the same node id in the same millisecond can hand out the same id, so give
+// each clone a node id of its own.
+#[cfg(feature = "std")]
+impl Clone for IdGenerator {
+    fn clone(&self) -> Self {
+        Self {
+            epoch: self.epoch,
+            epoch_bits: self.epoch_bits,
+            node_bits: self.node_bits,
+            shard_bits: self.shard_bits,
+            shard_checksum_bits: self.shard_checksum_bits,
+            shard2_bits: self.shard2_bits,
+            max_nodes: self.max_nodes,
+            config_id: self.config_id,
+            config_bits: self.config_bits,
+            inc_bits: self.inc_bits,
+            sequences: match &self.sequences {
+                Sequences::Shared(_) => Sequences::Shared(Sequence::new()),
+                Sequences::PerNode(sequences) => {
+                    Sequences::PerNode(sequences.iter().map(|_| Sequence::new()).collect())
+                }
             },
+            clock: Arc::clone(&self.clock),
+            metrics: Arc::clone(&self.metrics),
+            monotonic_tolerance: self.monotonic_tolerance,
+            fixed_node: self.fixed_node,
+            exhaustion_threshold: self.exhaustion_threshold,
+            rollover_strategy: self.rollover_strategy,
+            reset_sequence_each_ms: self.reset_sequence_each_ms,
+            deterministic: self.deterministic,
+            on_exhaustion: self.on_exhaustion.clone(),
+            #[cfg(test)]
+            rollover_wait_hook: self.rollover_wait_hook.clone(),
+            salt_bits: self.salt_bits,
+            salt: self.salt,
+            #[cfg(feature = "secure")]
+            random_window: Mutex::new(RandomWindow::default()),
+            backfill: Mutex::default(),
         }
     }
+}
 
-    pub fn derive_sharded_id(&self, original_id: u64, shard: u16) -> u64 {
-        if self.shard_bits == 0 {
-            panic!("This configuration doesn't support sharding");
-        }
+// The salt for a generator with `salt_bits` of salt. Without the `salt`
+// feature there's no randomness to draw from, so the bits stay 0 and only
+// keep their place in the layout.
+#[cfg(feature = "salt")]
+fn draw_salt(salt_bits: u8) -> u64 {
+    if salt_bits == 0 {
+        return 0;
+    }
+    let mut salt = [0; 8];
+    getrandom::getrandom(&mut salt).unwrap_or_else(|e| panic!("Couldn't draw a salt: {}", e));
+    u64::from_le_bytes(salt) & field_mask(salt_bits)
+}
 
-        if shard as u64 >= (1 << self.shard_bits) {
-            panic!("Shard number exceeds maximum");
-        }
+#[cfg(all(feature = "std", not(feature = "salt")))]
+fn draw_salt(_salt_bits: u8) -> u64 {
+    0
+}
 
-        let shard_shift = 13;
-        let shard_width = self.shard_bits;
+#[cfg(feature = "secure")]
+fn random_u64() -> u64 {
+    let mut bytes = [0; 8];
+    getrandom::getrandom(&mut bytes)
+        .unwrap_or_else(|e| panic!("Couldn't draw a random sequence: {}", e));
+    u64::from_le_bytes(bytes)
+}
 
-        let shard_mask = ((1u64 << shard_width) - 1) << shard_shift;
+// The sequence values `next_id_random` has drawn in the millisecond it is
+// issuing into, so a repeated draw can be retried
+#[cfg(feature = "secure")]
+#[derive(Default)]
+struct RandomWindow {
+    millis: u64,
+    used: std::collections::HashSet<u64>,
+    // Whether the exhaustion callback has been told this window is spent
+    reported: bool,
+}
 
-        let base_id = original_id & !shard_mask;
+#[cfg(feature = "std")]
+// Counter state for one stream of ids: the raw counter plus the millisecond
+// window it is currently issuing into. Both are updated together under one
+// lock; a separate atomic counter can be lapped by a preempted thread and
+// hand the same sequence number out twice in one millisecond.
+// `AtomicIdGenerator` packs the two into one word instead, for a lock-free
+// path.
+struct Sequence(Mutex<SequenceState>);
 
-        let shard_part = ((shard as u64) & ((1 << shard_width) - 1)) << shard_shift;
+#[cfg(feature = "std")]
+#[derive(Clone, Copy)]
+struct SequenceState {
+    next_id: u64,
+    window_millis: u64,
+    window_start: u64,
+    // The window live ids were first issued in. `next_id_at` backfills the
+    // milliseconds before it, which no live id can have used.
+    live_since: Option<u64>,
+    // The last spent window the exhaustion callback was told about
+    exhaustion_reported: Option<u64>,
+}
 
-        base_id | shard_part
+#[cfg(feature = "std")]
+impl Sequence {
+    fn new() -> Self {
+        Self::starting_at(0)
     }
 
-    pub fn decode_id(&self, id: u64) -> DecodedId {
-        let config_id = (id & 0b111) as u8;
-        let incrementing_id = (id >> 3) & ((1 << 10) - 1);
+    fn starting_at(next_id: u64) -> Self {
+        Self(Mutex::new(SequenceState::starting_at(next_id)))
+    }
 
-        // Shard bits come after incrementing id
-        let shard_id = if self.shard_bits > 0 {
-            ((id >> (3 + 10)) & ((1 << self.shard_bits) - 1)) as u16
-        } else {
-            0
-        };
+    fn lock(&self) -> MutexGuard<'_, SequenceState> {
+        // The state is plain integers, so a panic elsewhere can't leave it torn
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
 
-        // Node id now comes after shard bits
-        let node_shift = 3 + 10 + self.shard_bits;
-        let node_id = (id >> node_shift) & ((1 << self.node_bits) - 1);
+    // The window bookkeeping behind `IdGenerator::try_reserve_sequence`.
+    // `now` gives the current time as an offset from the epoch, from the
+    // clock or the state itself; it is called under the lock so windows only
+    // ever move forward unless the clock itself does not.
+    fn try_reserve(
+        &self,
+        now: impl FnOnce(&SequenceState) -> Result<u64, GenError>,
+        policy: WindowPolicy,
+        wanted: u64,
+    ) -> Result<Option<(u64, u64, u64)>, GenError> {
+        let mut state = self.lock();
+        let now = now(&state)?;
+        state.reserve(now, policy, wanted)
+    }
+}
 
-        // Time comes after node id
-        let time_shift = node_shift + self.node_bits;
-        let time = (id >> time_shift) & ((1 << self.epoch_bits) - 1);
+// How a sequence moves from one millisecond window to the next
+#[cfg(feature = "std")]
+#[derive(Clone, Copy)]
+struct WindowPolicy {
+    inc_bits: u8,
+    monotonic_tolerance: Option<u64>,
+    // Start each new millisecond at sequence value 0, as classic snowflakes
+    // do, rather than carrying on from the last
+    reset_each_ms: bool,
+}
 
-        DecodedId {
-            time,
-            node_id,
-            shard_id,
-            incrementing_id,
-            config_id,
+#[cfg(feature = "std")]
+impl WindowPolicy {
+    fn strict(inc_bits: u8) -> Self {
+        Self {
+            inc_bits,
+            monotonic_tolerance: None,
+            reset_each_ms: false,
         }
     }
+}
 
-    fn generate_id(&self, node_id: u16, incrementing_id: u16) -> u64 {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards");
-        let millis = now.as_millis() as u64;
-        let time_since_epoch = millis.checked_sub(self.epoch).expect("Time went backwards");
+#[cfg(feature = "std")]
+impl SequenceState {
+    fn starting_at(next_id: u64) -> Self {
+        Self {
+            next_id,
+            window_millis: 0,
+            window_start: next_id,
+            live_since: None,
+            exhaustion_reported: None,
+        }
+    }
 
-        // Start with config bits (lowest 3)
-        let config_part = (self.config_id as u64) & 0b111;
+    fn reserve(
+        &mut self,
+        now: u64,
+        policy: WindowPolicy,
+        wanted: u64,
+    ) -> Result<Option<(u64, u64, u64)>, GenError> {
+        let window_size: u64 = 1 << policy.inc_bits;
+        self.advance_window(now, policy)?;
 
-        // Incrementing id next (10 bits)
-        let inc_part = ((incrementing_id as u64) & ((1 << 10) - 1)) << 3;
+        let used = self.next_id.wrapping_sub(self.window_start);
+        if used >= window_size {
+            // Every value in this millisecond is spent
+            return Ok(None);
+        }
 
-        // Shard bits are 0 for non-sharded configs (comes after incrementing id)
-        let shard_shift = 3 + 10;
+        let granted = wanted.min(window_size - used);
+        let first = self.next_id;
+        self.next_id = first.wrapping_add(granted);
+        self.live_since.get_or_insert(self.window_millis);
+        Ok(Some((self.window_millis, first, granted)))
+    }
 
-        // Node id comes after shard bits
-        let node_shift = shard_shift + self.shard_bits;
-        let node_part = ((node_id as u64) & ((1 << self.node_bits) - 1)) << node_shift;
+    // Opens a new window if the clock has moved on since the last one
+    fn advance_window(&mut self, now: u64, policy: WindowPolicy) -> Result<(), GenError> {
+        if now > self.window_millis {
+            self.window_millis = now;
+            self.window_start = self.next_id;
+            // Monotonic ids have to increase within a window too, which a
+            // sequence wrapping past the top of its field would break
+            if policy.reset_each_ms || policy.monotonic_tolerance.is_some() {
+                self.align_window(policy.inc_bits);
+            }
+        } else if now < self.window_millis {
+            // In monotonic mode a small regression keeps issuing from the
+            // last window, bumping the sequence instead of the time
+            let behind = self.window_millis - now;
+            if policy.monotonic_tolerance.is_none_or(|t| behind > t) {
+                return Err(GenError::ClockWentBackwards);
+            }
+        }
+        Ok(())
+    }
 
-        // Time is highest
-        let time_shift = node_shift + self.node_bits;
-        let time_part = (time_since_epoch & ((1u64 << self.epoch_bits) - 1)) << time_shift;
+    // Whether the current window is spent and nobody has reported it yet, in
+    // which case the caller reports it. However many callers end up waiting
+    // on one spent millisecond, only the first gets `true`.
+    fn report_exhaustion(&mut self, inc_bits: u8) -> bool {
+        let spent = self.next_id.wrapping_sub(self.window_start) > field_mask(inc_bits);
+        if !spent || self.exhaustion_reported == Some(self.window_millis) {
+            return false;
+        }
+        self.exhaustion_reported = Some(self.window_millis);
+        true
+    }
 
-        time_part | node_part | inc_part | config_part
+    // Where a deterministic generator's time has got to: `start` until the
+    // first id, then the current window's millisecond until its sequence is
+    // spent, and the next one after that
+    fn deterministic_now(&self, start: u64, inc_bits: u8) -> u64 {
+        if self.live_since.is_none() {
+            return start;
+        }
+        let used = self.next_id.wrapping_sub(self.window_start);
+        if used > field_mask(inc_bits) {
+            self.window_millis + 1
+        } else {
+            self.window_millis
+        }
     }
 
-    pub fn next_id(&self, node_id: u16) -> u64 {
-        let incrementing_id = self.next_id.fetch_add(1, Ordering::SeqCst) & ((1 << 10) - 1);
-        self.generate_id(node_id, incrementing_id)
+    // Moves an unused window up to the next counter value whose sequence
+    // field reads 0. The raw counter still only goes forward, so the
+    // skipped values are never handed out in this millisecond.
+    fn align_window(&mut self, inc_bits: u8) {
+        let mask = field_mask(inc_bits);
+        if self.next_id == self.window_start {
+            self.next_id = self.next_id.wrapping_add(mask) & !mask;
+            self.window_start = self.next_id;
+        }
     }
 }
 
-#[cfg(target_arch = "wasm32")]
-#[wasm_bindgen]
-pub struct WasmIdGenerator {
-    generator: IdGenerator,
+#[cfg(feature = "std")]
+enum Sequences {
+    Shared(Sequence),
+    PerNode(Box<[Sequence]>),
 }
 
-#[cfg(target_arch = "wasm32")]
-#[wasm_bindgen]
-impl WasmIdGenerator {
-    #[wasm_bindgen(constructor)]
-    pub fn new(preset_type: u8, epoch: u64) -> Result<WasmIdGenerator, JsValue> {
-        let preset = match preset_type {
-            0 => ConfigPreset::ShortEpochMaxNodes,
-            1 => ConfigPreset::ShardedConfig,
-            _ => return Err(JsValue::from_str("Invalid preset type")),
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub struct DecodedId {
+    pub time: u64,
+    pub node_id: u64,
+    pub shard_id: u16,
+    pub incrementing_id: u64,
+    pub config_id: u8,
+}
+
+impl DecodedId {
+    pub const BYTES: usize = 27;
+
+    // Saturates rather than overflow for a time decoded with the wrong epoch
+    pub fn timestamp_millis(&self, epoch: u64) -> u64 {
+        epoch.saturating_add(self.time)
+    }
+
+    // Big-endian fields in declaration order: time 8 | node_id 8 | shard_id 2
+    // | incrementing_id 8 | config_id 1
+    pub fn to_bytes(&self) -> [u8; DecodedId::BYTES] {
+        let mut bytes = [0; DecodedId::BYTES];
+        bytes[0..8].copy_from_slice(&self.time.to_be_bytes());
+        bytes[8..16].copy_from_slice(&self.node_id.to_be_bytes());
+        bytes[16..18].copy_from_slice(&self.shard_id.to_be_bytes());
+        bytes[18..26].copy_from_slice(&self.incrementing_id.to_be_bytes());
+        bytes[26] = self.config_id;
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; DecodedId::BYTES]) -> DecodedId {
+        let u64_at = |at: usize| {
+            let mut field = [0; 8];
+            field.copy_from_slice(&bytes[at..at + 8]);
+            u64::from_be_bytes(field)
         };
+        DecodedId {
+            time: u64_at(0),
+            node_id: u64_at(8),
+            shard_id: u16::from_be_bytes([bytes[16], bytes[17]]),
+            incrementing_id: u64_at(18),
+            config_id: bytes[26],
+        }
+    }
 
-        Ok(WasmIdGenerator {
-            generator: IdGenerator::new(preset, epoch),
-        })
+    #[cfg(feature = "std")]
+    pub fn system_time(&self, epoch: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.timestamp_millis(epoch))
     }
 
-    #[wasm_bindgen]
-    pub fn next_id(&self, node_id: u16) -> u64 {
-        self.generator.next_id(node_id)
+    #[cfg(feature = "chrono")]
+    pub fn datetime(&self, epoch: chrono::DateTime<chrono::Utc>) -> chrono::DateTime<chrono::Utc> {
+        epoch + chrono::Duration::milliseconds(self.time as i64)
     }
 
-    #[wasm_bindgen]
-    pub fn decode_id(&self, id: u64) -> Result<JsValue, JsValue> {
-        let decoded = self.generator.decode_id(id);
-        serde_wasm_bindgen::to_value(&decoded).map_err(|e| JsValue::from_str(&e.to_string()))
+    // Picks the layout from the id's config bits. Only the presets with their
+    // own config id can be told apart this way: Snowflake ids have no config
+    // field and Custom layouts choose theirs freely.
+    pub fn from_id_auto(id: u64) -> Result<DecodedId, DecodeError> {
+        let preset = match config_id_of(id) {
+            1 => ConfigPreset::ShardedConfig,
+            3 => ConfigPreset::ShortEpochMaxNodes,
+            4 => ConfigPreset::ShardedConfigWide,
+            config_id => return Err(DecodeError::UnknownConfig(config_id)),
+        };
+        Ok(IdConfig::new(preset, 0).decode(id))
     }
 
-    #[wasm_bindgen]
-    pub fn derive_sharded_id(&self, original_id: u64, shard: u16) -> Result<u64, JsValue> {
-        if self.generator.shard_bits == 0 {
-            return Err(JsValue::from_str(
-                "This configuration doesn't support sharding",
-            ));
-        }
+    // `from_id_auto` for an id read back from a signed column
+    pub fn from_i64(id: i64) -> Result<DecodedId, DecodeError> {
+        DecodedId::from_id_auto(id_from_i64(id))
+    }
+}
 
-        if shard as u64 >= (1 << self.generator.shard_bits) {
-            return Err(JsValue::from_str("Shard number exceeds maximum"));
-        }
+impl TryFrom<u64> for DecodedId {
+    type Error = DecodeError;
 
-        Ok(self.generator.derive_sharded_id(original_id, shard))
+    fn try_from(id: u64) -> Result<Self, Self::Error> {
+        DecodedId::from_id_auto(id)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl fmt::Display for DecodedId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "t={}/n={}/s={}/i={}/c={}",
+            self.time, self.node_id, self.shard_id, self.incrementing_id, self.config_id
+        )
+    }
+}
 
-    #[test]
-    fn test_initialization() {
-        let gen_short = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
-        assert_eq!(gen_short.epoch, DEFAULT_EPOCH);
-        assert_eq!(gen_short.epoch_bits, 37);
-        assert_eq!(gen_short.node_bits, 14);
-        assert_eq!(gen_short.max_nodes, 16384);
-        assert_eq!(gen_short.config_id, 3);
-        assert_eq!(gen_short.shard_bits, 0);
+/// Where one field of an id sits: `width` bits starting at bit `offset`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LayoutField {
+    pub name: &'static str,
+    pub offset: u8,
+    pub width: u8,
+}
 
-        let custom_epoch = 1609459200000;
-        let custom_epoch_bits = 36;
-        let custom_node_bits = 13;
-        let custom_shard_bits = 2;
-        let custom_config_id = 1;
-        let gen_custom = IdGenerator::new(
-            ConfigPreset::Custom(
-                custom_epoch,
-                custom_epoch_bits,
-                custom_node_bits,
-                custom_shard_bits, // Add this parameter
-                custom_config_id,
-            ),
-            custom_epoch,
-        );
-        assert_eq!(gen_custom.epoch, custom_epoch);
-        assert_eq!(gen_custom.epoch_bits, custom_epoch_bits);
-        assert_eq!(gen_custom.node_bits, custom_node_bits);
-        assert_eq!(gen_custom.shard_bits, custom_shard_bits);
-        assert_eq!(gen_custom.max_nodes, 8192);
-        assert_eq!(gen_custom.config_id, custom_config_id);
+/// Every field of a layout from the lowest bit up, for rendering bit
+/// diagrams. Fields a layout doesn't use are kept with a width of 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub fields: [LayoutField; 6],
+}
+
+impl Layout {
+    pub fn total_bits(&self) -> u32 {
+        let [config, inc, shard, node, time, shard_checksum] = self.fields.map(|f| f.width);
+        IdConfig {
+            epoch: 0,
+            epoch_bits: time,
+            node_bits: node,
+            shard_bits: shard,
+            config_id: 0,
+            config_bits: config,
+            inc_bits: inc,
+            shard_checksum_bits: shard_checksum,
+            shard2_bits: 0,
+            salt_bits: 0,
+        }
+        .total_bits()
     }
+}
 
-    #[test]
-    fn test_id_uniqueness_and_sequence() {
-        let gen = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
-        let mut last_id = 0;
-        for _ in 0..100 {
-            let new_id = gen.next_id(1);
-            assert!(new_id > last_id);
-            last_id = new_id;
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, field) in self.fields.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}@{}:{}", field.name, field.offset, field.width)?;
         }
+        Ok(())
     }
+}
 
-    #[test]
-    fn test_id_composition() {
-        let gen = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
-        let id = gen.next_id(1);
-        let decoded = gen.decode_id(id);
-        assert_eq!(decoded.config_id, 3);
-        assert!(
-            decoded.time
-                <= (SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_millis() as u64
-                    - DEFAULT_EPOCH)
-        );
-        assert_eq!(decoded.node_id, 1);
+/// A node id checked against a generator's node bits. The only way to get
+/// one is a generator's `node_id`, which refuses values the node field can't
+/// hold, so a `NodeId` always fits the layout it was made for. Generators
+/// still compare it with their own node count, for one made by a generator
+/// with a wider node field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(u32);
+
+impl NodeId {
+    fn checked(raw: u64, max_nodes: u32) -> Result<Self, GenError> {
+        if raw >= max_nodes as u64 {
+            return Err(GenError::NodeIdOutOfRange {
+                node_id: raw,
+                max_nodes,
+            });
+        }
+        Ok(Self(raw as u32))
     }
 
-    #[test]
-    fn test_boundary_conditions() {
-        let gen = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
-        // Test maximum node ID
-        let max_node_id = gen.max_nodes - 1;
-        let id = gen.next_id(max_node_id);
-        let decoded = gen.decode_id(id);
-        assert_eq!(decoded.node_id, max_node_id as u64);
+    pub fn get(self) -> u32 {
+        self.0
+    }
+}
 
-        // Test maximum incrementing ID
-        let max_inc_id = (1 << 10) - 1; // 10 bits max
-        let id = gen.generate_id(1, max_inc_id as u16);
-        let decoded = gen.decode_id(id);
-        assert_eq!(decoded.incrementing_id, max_inc_id as u64);
+// How many node ids a node field `node_bits` wide takes. Node counts are u32,
+// so a field past 31 bits leaves the bits above unused.
+const fn node_count(node_bits: u8) -> u32 {
+    1 << if node_bits < 31 { node_bits } else { 31 }
+}
+
+/// An id as its own type, so it can't be mixed up with other integers. It
+/// orders, prints, parses and serializes as the plain `u64`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct GenId(pub u64);
+
+impl fmt::Display for GenId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
     }
+}
 
-    #[test]
-    fn test_decode_correctness() {
-        let gen = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
-        let id = gen.next_id(1);
-        let decoded = gen.decode_id(id);
-        assert_eq!(decoded.node_id, 1);
-        assert_eq!(decoded.config_id, 3);
+impl FromStr for GenId {
+    type Err = core::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(GenId)
     }
+}
 
-    #[test]
-    #[should_panic(expected = "Time went backwards")]
-    fn test_time_travel_resilience() {
-        let gen = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, u64::MAX);
-        let _ = gen.next_id(1);
+impl From<u64> for GenId {
+    fn from(id: u64) -> Self {
+        Self(id)
     }
+}
 
-    #[test]
-    fn test_node_id_encoding() {
-        let gen = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
-        let test_node_ids = [0, 1, 2, 3, 4, 5, 6, gen.max_nodes / 2, gen.max_nodes - 1];
+impl From<GenId> for u64 {
+    fn from(id: GenId) -> Self {
+        id.0
+    }
+}
 
-        for &node_id in &test_node_ids {
-            let id = gen.next_id(node_id);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseDecodedIdError {
+    /// The named field is absent or out of order.
+    MissingField(&'static str),
+    /// The named field's value isn't a valid number for that field.
+    InvalidValue(&'static str),
+    /// There is more input after the last field.
+    TrailingInput,
+}
+
+impl fmt::Display for ParseDecodedIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseDecodedIdError::MissingField(field) => write!(f, "Missing field {:?}", field),
+            ParseDecodedIdError::InvalidValue(field) => {
+                write!(f, "Invalid value for field {:?}", field)
+            }
+            ParseDecodedIdError::TrailingInput => write!(f, "Unexpected input after last field"),
+        }
+    }
+}
+
+impl core::error::Error for ParseDecodedIdError {}
+
+impl FromStr for DecodedId {
+    type Err = ParseDecodedIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('/');
+        let mut field = |name: &'static str| {
+            parts
+                .next()
+                .and_then(|part| part.strip_prefix(name))
+                .and_then(|part| part.strip_prefix('='))
+                .ok_or(ParseDecodedIdError::MissingField(name))
+                .map(|value| (name, value))
+        };
+        fn parse<T: FromStr>(
+            (name, value): (&'static str, &str),
+        ) -> Result<T, ParseDecodedIdError> {
+            value
+                .parse()
+                .map_err(|_| ParseDecodedIdError::InvalidValue(name))
+        }
+
+        let decoded = DecodedId {
+            time: parse(field("t")?)?,
+            node_id: parse(field("n")?)?,
+            shard_id: parse(field("s")?)?,
+            incrementing_id: parse(field("i")?)?,
+            config_id: parse(field("c")?)?,
+        };
+
+        if parts.next().is_some() {
+            return Err(ParseDecodedIdError::TrailingInput);
+        }
+        Ok(decoded)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GenError {
+    /// The system clock reads earlier than the generator's epoch.
+    ClockBehindEpoch { epoch: u64, now: u64 },
+    /// The system clock moved behind the last millisecond an id was issued in.
+    ClockWentBackwards,
+    /// The node id doesn't fit in the configured node bits.
+    NodeIdOutOfRange { node_id: u64, max_nodes: u32 },
+    /// The timestamp is past the last millisecond the time field can hold.
+    TimestampOutOfRange { timestamp_millis: u64, max: u64 },
+    /// More of the time field is used up than the exhaustion threshold allows.
+    EpochExhausted { elapsed: u64, max: u64 },
+    /// The current millisecond can't fit a block of `requested` contiguous ids.
+    BlockUnavailable { requested: u16, remaining: u64 },
+    /// A backfilled millisecond is one live ids may already have used.
+    BackfillOverlapsLive {
+        timestamp_millis: u64,
+        live_since: u64,
+    },
+    /// Every sequence value in a backfilled millisecond is taken.
+    BackfillExhausted { timestamp_millis: u64 },
+}
+
+impl fmt::Display for GenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GenError::ClockBehindEpoch { epoch, now } => write!(
+                f,
+                "Time went backwards: clock reads {}ms, which is before the epoch {}ms",
+                now, epoch
+            ),
+            GenError::ClockWentBackwards => write!(f, "Time went backwards"),
+            GenError::NodeIdOutOfRange { node_id, max_nodes } => write!(
+                f,
+                "Node id {} exceeds maximum, only {} nodes are supported",
+                node_id, max_nodes
+            ),
+            GenError::TimestampOutOfRange {
+                timestamp_millis,
+                max,
+            } => write!(
+                f,
+                "Timestamp {}ms is past the last one the time field holds, {}ms",
+                timestamp_millis, max
+            ),
+            GenError::EpochExhausted { elapsed, max } => write!(
+                f,
+                "Time field is nearly exhausted: {}ms of {}ms since the epoch are used",
+                elapsed, max
+            ),
+            GenError::BlockUnavailable {
+                requested,
+                remaining,
+            } => write!(
+                f,
+                "Can't reserve {} contiguous ids, the current millisecond has room for {}",
+                requested, remaining
+            ),
+            GenError::BackfillOverlapsLive {
+                timestamp_millis,
+                live_since,
+            } => write!(
+                f,
+                "Can't backfill {}ms, live ids have been issued since {}ms",
+                timestamp_millis, live_since
+            ),
+            GenError::BackfillExhausted { timestamp_millis } => write!(
+                f,
+                "No sequence values are left to backfill {}ms with",
+                timestamp_millis
+            ),
+        }
+    }
+}
+
+impl core::error::Error for GenError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The fields need more than the 64 bits available in an id.
+    BitBudgetExceeded {
+        epoch_bits: u8,
+        node_bits: u8,
+        shard_bits: u8,
+        inc_bits: u8,
+        config_bits: u8,
+        shard_checksum_bits: u8,
+        overflow: u32,
+    },
+    /// The config id doesn't fit in the layout's config bits.
+    ConfigIdTooLarge { config_id: u8, config_bits: u8 },
+    /// The named field has no bits, so every id would carry 0 in it.
+    ZeroWidthField(&'static str),
+    /// The sequence field is wider than the counter behind it.
+    SequenceTooWide { inc_bits: u8, max: u8 },
+    /// The salt takes up more than the whole node field.
+    SaltTooWide { salt_bits: u8, node_bits: u8 },
+    /// The secondary shard takes up more than the whole shard field.
+    SecondaryShardTooWide { shard2_bits: u8, shard_bits: u8 },
+    /// The named environment variable is set but isn't a number that fits.
+    InvalidEnvVar(&'static str),
+    /// The epoch is later than the clock reads, so no id could be issued yet.
+    EpochInFuture { epoch: u64, now: u64 },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::BitBudgetExceeded {
+                epoch_bits,
+                node_bits,
+                shard_bits,
+                inc_bits,
+                config_bits,
+                shard_checksum_bits,
+                overflow,
+            } => write!(
+                f,
+                "Layout needs {} bits ({} config_bits + {} inc_bits + {} shard_bits + {} node_bits + {} epoch_bits + {} shard_checksum_bits), {} more than the 64 available",
+                64 + overflow,
+                config_bits,
+                inc_bits,
+                shard_bits,
+                node_bits,
+                epoch_bits,
+                shard_checksum_bits,
+                overflow
+            ),
+            ConfigError::ConfigIdTooLarge {
+                config_id,
+                config_bits,
+            } => write!(
+                f,
+                "Config id {} doesn't fit in {} bits, it must be at most {}",
+                config_id,
+                config_bits,
+                field_mask(*config_bits)
+            ),
+            ConfigError::SequenceTooWide { inc_bits, max } => write!(
+                f,
+                "inc_bits is {}, but the sequence counter holds at most {} bits",
+                inc_bits, max
+            ),
+            ConfigError::SaltTooWide {
+                salt_bits,
+                node_bits,
+            } => write!(
+                f,
+                "salt_bits is {}, but the node field it comes out of has only {} bits",
+                salt_bits, node_bits
+            ),
+            ConfigError::SecondaryShardTooWide {
+                shard2_bits,
+                shard_bits,
+            } => write!(
+                f,
+                "shard2_bits is {}, but the shard field it comes out of has only {} bits",
+                shard2_bits, shard_bits
+            ),
+            ConfigError::ZeroWidthField(field) => {
+                write!(f, "{} must be at least 1, a zero-width field is always 0", field)
+            }
+            ConfigError::InvalidEnvVar(name) => {
+                write!(f, "{} is set, but isn't a number in range for it", name)
+            }
+            ConfigError::EpochInFuture { epoch, now } => write!(
+                f,
+                "Epoch {}ms is after the current time {}ms",
+                epoch, now
+            ),
+        }
+    }
+}
+
+impl core::error::Error for ConfigError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The input string is empty.
+    Empty,
+    /// The input contains a character outside the encoding's alphabet.
+    InvalidCharacter(char),
+    /// The input has leading zeros, so it isn't the canonical encoding.
+    LeadingZero,
+    /// The input represents a value larger than `u64::MAX`.
+    Overflow,
+    /// The id was generated under a different config than the decoder's.
+    ConfigMismatch { expected: u8, found: u8 },
+    /// The input isn't the fixed length the encoding always produces.
+    InvalidLength { expected: usize, found: usize },
+    /// The id's config id doesn't belong to any built-in preset.
+    UnknownConfig(u8),
+    /// The id's shard doesn't match the checksum stored alongside it.
+    ShardChecksumMismatch,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Empty => write!(f, "Encoded id is empty"),
+            DecodeError::InvalidCharacter(c) => {
+                write!(f, "Invalid character {:?} in encoded id", c)
+            }
+            DecodeError::LeadingZero => write!(f, "Encoded id has leading zeros"),
+            DecodeError::Overflow => write!(f, "Encoded id overflows a u64"),
+            DecodeError::ConfigMismatch { expected, found } => write!(
+                f,
+                "Id has config id {} but the decoder expects {}",
+                found, expected
+            ),
+            DecodeError::InvalidLength { expected, found } => write!(
+                f,
+                "Encoded id is {} characters long, expected {}",
+                found, expected
+            ),
+            DecodeError::UnknownConfig(config_id) => {
+                write!(f, "Config id {} doesn't match a built-in preset", config_id)
+            }
+            DecodeError::ShardChecksumMismatch => {
+                write!(f, "Id's shard doesn't match its shard checksum")
+            }
+        }
+    }
+}
+
+impl core::error::Error for DecodeError {}
+
+#[cfg(feature = "std")]
+const BASE62_ALPHABET: &[u8; 62] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+#[cfg(feature = "std")]
+pub fn encode_base62(id: u64) -> String {
+    if id == 0 {
+        return "0".to_string();
+    }
+
+    // u64::MAX is 11 digits in base62
+    let mut digits = [0u8; 11];
+    let mut len = 0;
+    let mut rest = id;
+    while rest > 0 {
+        digits[len] = BASE62_ALPHABET[(rest % 62) as usize];
+        rest /= 62;
+        len += 1;
+    }
+
+    digits[..len].iter().rev().map(|&d| d as char).collect()
+}
+
+pub fn decode_base62(s: &str) -> Result<u64, DecodeError> {
+    if s.is_empty() {
+        return Err(DecodeError::Empty);
+    }
+    if s.len() > 1 && s.starts_with('0') {
+        return Err(DecodeError::LeadingZero);
+    }
+
+    s.chars().try_fold(0u64, |acc, c| {
+        let digit = match c {
+            '0'..='9' => c as u64 - '0' as u64,
+            'A'..='Z' => c as u64 - 'A' as u64 + 10,
+            'a'..='z' => c as u64 - 'a' as u64 + 36,
+            _ => return Err(DecodeError::InvalidCharacter(c)),
+        };
+        acc.checked_mul(62)
+            .and_then(|acc| acc.checked_add(digit))
+            .ok_or(DecodeError::Overflow)
+    })
+}
+
+// Crockford's base32 alphabet is in ASCII order, so with a fixed width the
+// strings sort the same way the ids do
+const SORTABLE_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+// 13 digits of 5 bits cover all 64; the first only ever holds the top 4
+const SORTABLE_LEN: usize = 13;
+
+#[cfg(feature = "std")]
+pub fn encode_sortable(id: u64) -> String {
+    (0..SORTABLE_LEN)
+        .rev()
+        .map(|digit| SORTABLE_ALPHABET[((id >> (digit * 5)) & 0x1f) as usize] as char)
+        .collect()
+}
+
+pub fn decode_sortable(s: &str) -> Result<u64, DecodeError> {
+    decode_base32(s, |c| c)
+}
+
+// The sortable encoding is already Crockford base32, so ids encode the same
+// way under either name. Decoding is where they differ: see `decode_crockford`.
+#[cfg(feature = "std")]
+pub fn encode_crockford(id: u64) -> String {
+    encode_sortable(id)
+}
+
+// Like `decode_sortable`, but as forgiving as Crockford's spec: lowercase is
+// accepted, and the look-alikes I and L read as 1 and O as 0
+pub fn decode_crockford(s: &str) -> Result<u64, DecodeError> {
+    decode_base32(s, |c| match c.to_ascii_uppercase() {
+        'I' | 'L' => '1',
+        'O' => '0',
+        c => c,
+    })
+}
+
+// `normalize` maps each character before it is looked up in the alphabet; an
+// error reports the character as it was written
+fn decode_base32(s: &str, normalize: impl Fn(char) -> char) -> Result<u64, DecodeError> {
+    if s.is_empty() {
+        return Err(DecodeError::Empty);
+    }
+    if s.len() != SORTABLE_LEN {
+        return Err(DecodeError::InvalidLength {
+            expected: SORTABLE_LEN,
+            found: s.len(),
+        });
+    }
+
+    s.chars().try_fold(0u64, |acc, c| {
+        let normalized = normalize(c);
+        let digit = SORTABLE_ALPHABET
+            .iter()
+            .position(|&d| d as char == normalized)
+            .ok_or(DecodeError::InvalidCharacter(c))?;
+        if acc >> 59 != 0 {
+            return Err(DecodeError::Overflow);
+        }
+        Ok((acc << 5) | digit as u64)
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShardError {
+    /// The layout has no shard bits.
+    ShardingUnsupported,
+    /// The shard doesn't fit in the layout's shard bits, which hold `max` shards.
+    ShardOutOfRange { shard: u16, max: u32 },
+}
+
+impl fmt::Display for ShardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShardError::ShardingUnsupported => {
+                write!(f, "This configuration doesn't support sharding")
+            }
+            ShardError::ShardOutOfRange { shard, max } => write!(
+                f,
+                "Shard number exceeds maximum: {} is not below {}",
+                shard, max
+            ),
+        }
+    }
+}
+
+impl core::error::Error for ShardError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReencodeError {
+    /// The named field's value needs more than the target layout's bits for it.
+    FieldTooWide {
+        field: &'static str,
+        value: u64,
+        bits: u8,
+    },
+    /// The id is from before the target layout's epoch.
+    BeforeEpoch { timestamp_millis: u64, epoch: u64 },
+}
+
+impl fmt::Display for ReencodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReencodeError::FieldTooWide { field, value, bits } => write!(
+                f,
+                "Field {:?} is {}, which doesn't fit in the target's {} bits",
+                field, value, bits
+            ),
+            ReencodeError::BeforeEpoch {
+                timestamp_millis,
+                epoch,
+            } => write!(
+                f,
+                "Id is from {}ms, which is before the target epoch {}ms",
+                timestamp_millis, epoch
+            ),
+        }
+    }
+}
+
+impl core::error::Error for ReencodeError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelfTestError {
+    /// The layout itself is invalid.
+    Layout(ConfigError),
+    /// The named field decoded to something other than what was encoded.
+    RoundTrip {
+        field: &'static str,
+        expected: u64,
+        found: u64,
+    },
+}
+
+impl fmt::Display for SelfTestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SelfTestError::Layout(err) => write!(f, "Invalid layout: {}", err),
+            SelfTestError::RoundTrip {
+                field,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Field {:?} encoded as {} but decoded as {}",
+                field, expected, found
+            ),
+        }
+    }
+}
+
+impl core::error::Error for SelfTestError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            SelfTestError::Layout(err) => Some(err),
+            SelfTestError::RoundTrip { .. } => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationError {
+    /// The id's config field doesn't hold this layout's config id.
+    ConfigMismatch { expected: u8, found: u8 },
+    /// The node field holds a node id the generator can't issue.
+    NodeOutOfRange { node_id: u64, max_nodes: u32 },
+    /// The shard field holds a shard the generator can't derive.
+    ShardOutOfRange { shard: u64, max: u32 },
+    /// The shard checksum doesn't match the shard.
+    ShardChecksumMismatch,
+    /// Bits above the layout's last field are set.
+    UnusedBitsSet,
+    /// The id's time is later than the allowed clock skew past now.
+    TimeInFuture { timestamp_millis: u64, limit: u64 },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::ConfigMismatch { expected, found } => write!(
+                f,
+                "Config id mismatch: expected {}, found {}",
+                expected, found
+            ),
+            ValidationError::NodeOutOfRange { node_id, max_nodes } => write!(
+                f,
+                "Node id {} exceeds maximum, only {} nodes are supported",
+                node_id, max_nodes
+            ),
+            ValidationError::ShardOutOfRange { shard, max } => write!(
+                f,
+                "Shard number exceeds maximum: {} is not below {}",
+                shard, max
+            ),
+            ValidationError::ShardChecksumMismatch => {
+                write!(f, "Shard checksum doesn't match the shard")
+            }
+            ValidationError::UnusedBitsSet => {
+                write!(f, "Bits above the layout's fields are set")
+            }
+            ValidationError::TimeInFuture {
+                timestamp_millis,
+                limit,
+            } => write!(
+                f,
+                "Id is from {}ms, which is past the latest allowed time {}ms",
+                timestamp_millis, limit
+            ),
+        }
+    }
+}
+
+impl core::error::Error for ValidationError {}
+
+/// Any of the crate's errors, for callers that handle them together. Each
+/// variant wraps the error the failing call returned, which `source` exposes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GenIdError {
+    Generate(GenError),
+    Config(ConfigError),
+    Decode(DecodeError),
+    Shard(ShardError),
+    Reencode(ReencodeError),
+    SelfTest(SelfTestError),
+    Validation(ValidationError),
+    #[cfg(feature = "cluster")]
+    Allocate(AllocError),
+}
+
+impl fmt::Display for GenIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GenIdError::Generate(err) => write!(f, "Couldn't generate an id: {}", err),
+            GenIdError::Config(err) => write!(f, "Invalid layout: {}", err),
+            GenIdError::Decode(err) => write!(f, "Couldn't decode the id: {}", err),
+            GenIdError::Shard(err) => write!(f, "Couldn't shard the id: {}", err),
+            GenIdError::Reencode(err) => write!(f, "Couldn't re-encode the id: {}", err),
+            GenIdError::SelfTest(err) => write!(f, "Self test failed: {}", err),
+            GenIdError::Validation(err) => write!(f, "Invalid id: {}", err),
+            #[cfg(feature = "cluster")]
+            GenIdError::Allocate(err) => write!(f, "Couldn't allocate a node id: {}", err),
+        }
+    }
+}
+
+impl core::error::Error for GenIdError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            GenIdError::Generate(err) => Some(err),
+            GenIdError::Config(err) => Some(err),
+            GenIdError::Decode(err) => Some(err),
+            GenIdError::Shard(err) => Some(err),
+            GenIdError::Reencode(err) => Some(err),
+            GenIdError::SelfTest(err) => Some(err),
+            GenIdError::Validation(err) => Some(err),
+            #[cfg(feature = "cluster")]
+            GenIdError::Allocate(err) => Some(err),
+        }
+    }
+}
+
+impl From<GenError> for GenIdError {
+    fn from(err: GenError) -> Self {
+        GenIdError::Generate(err)
+    }
+}
+
+impl From<ConfigError> for GenIdError {
+    fn from(err: ConfigError) -> Self {
+        GenIdError::Config(err)
+    }
+}
+
+impl From<DecodeError> for GenIdError {
+    fn from(err: DecodeError) -> Self {
+        GenIdError::Decode(err)
+    }
+}
+
+impl From<ShardError> for GenIdError {
+    fn from(err: ShardError) -> Self {
+        GenIdError::Shard(err)
+    }
+}
+
+impl From<ReencodeError> for GenIdError {
+    fn from(err: ReencodeError) -> Self {
+        GenIdError::Reencode(err)
+    }
+}
+
+impl From<SelfTestError> for GenIdError {
+    fn from(err: SelfTestError) -> Self {
+        GenIdError::SelfTest(err)
+    }
+}
+
+impl From<ValidationError> for GenIdError {
+    fn from(err: ValidationError) -> Self {
+        GenIdError::Validation(err)
+    }
+}
+
+#[cfg(feature = "cluster")]
+impl From<AllocError> for GenIdError {
+    fn from(err: AllocError) -> Self {
+        GenIdError::Allocate(err)
+    }
+}
+
+// 64-bit FNV-1a, which unlike `std::hash::DefaultHasher` is fixed, so keys
+// hash the same in every build and on every platform
+#[cfg(feature = "std")]
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// The lowest CONFIG_BITS bits, where every layout with a config field keeps
+// it, so the config id can be read before knowing which generator an id came
+// from. Whether the id has a config field at all can't be told from the bits:
+// for Snowflake and other config-less ids this is the bottom of the sequence.
+// `IdGenerator::config_id_of` knows the layout and gives 0 for those.
+pub fn config_id_of(id: u64) -> u8 {
+    (id & ((1 << CONFIG_BITS) - 1)) as u8
+}
+
+// The raw id in a fixed byte order, for binary messages read by other
+// languages. Big-endian bytes sort the same way as the ids.
+pub fn id_to_be_bytes(id: u64) -> [u8; 8] {
+    id.to_be_bytes()
+}
+
+pub fn id_from_be_bytes(bytes: [u8; 8]) -> u64 {
+    u64::from_be_bytes(bytes)
+}
+
+pub fn id_to_le_bytes(id: u64) -> [u8; 8] {
+    id.to_le_bytes()
+}
+
+pub fn id_from_le_bytes(bytes: [u8; 8]) -> u64 {
+    u64::from_le_bytes(bytes)
+}
+
+// For databases that store ids in a signed 64-bit column such as BIGINT. The
+// bits are kept as they are, so ids with the top bit set, the ones at or past
+// 1 << 63, come out negative and don't sort after smaller ids as i64s.
+pub fn id_to_i64(id: u64) -> i64 {
+    id as i64
+}
+
+pub fn id_from_i64(id: i64) -> u64 {
+    id as u64
+}
+
+// Best guess at which built-in preset an id came from, for telling apart ids
+// in a mixed log. The presets with their own config id are tried first, then
+// Snowflake, which has no config field and so could be any id. A guess has to
+// leave the bits above its time field clear and, with std, have a time no
+// later than now counted from 1970, the earliest epoch there can be.
+pub fn guess_preset(id: u64) -> Option<ConfigPreset> {
+    let by_config_id: Option<fn() -> ConfigPreset> = match config_id_of(id) {
+        1 => Some(|| ConfigPreset::ShardedConfig),
+        3 => Some(|| ConfigPreset::ShortEpochMaxNodes),
+        4 => Some(|| ConfigPreset::ShardedConfigWide),
+        _ => None,
+    };
+    let snowflake: fn() -> ConfigPreset = || ConfigPreset::Snowflake;
+    by_config_id
+        .into_iter()
+        .chain([snowflake])
+        .find(|preset| {
+            let config = IdConfig::new(preset(), 0);
+            read_field(id, config.shard_checksum_shift(), 64) == 0
+                && plausible_time(config.decode(id).time)
+        })
+        .map(|preset| preset())
+}
+
+#[cfg(feature = "std")]
+fn plausible_time(time: u64) -> bool {
+    time <= SystemClock.now_millis()
+}
+
+#[cfg(not(feature = "std"))]
+fn plausible_time(_time: u64) -> bool {
+    true
+}
+
+// Unpacks an id given just its epoch and layout. The time field stays an
+// offset from the epoch, the same as from `IdGenerator::decode_id`; call
+// `timestamp_millis(epoch)` on the result for the absolute time. A salt
+// comes back as part of the node id; decode salted ids with an `IdConfig`
+// that has `salt_bits` set.
+pub fn decode_with(
+    id: u64,
+    epoch: u64,
+    epoch_bits: u8,
+    node_bits: u8,
+    shard_bits: u8,
+) -> DecodedId {
+    IdConfig {
+        epoch,
+        epoch_bits,
+        node_bits,
+        shard_bits,
+        config_id: 0,
+        config_bits: CONFIG_BITS,
+        inc_bits: INC_BITS,
+        shard_checksum_bits: 0,
+        shard2_bits: 0,
+        salt_bits: 0,
+    }
+    .decode(id)
+}
+
+// Unpacks a Twitter/Discord style snowflake, which has no config field. The
+// node id holds the worker id in its high 5 bits and the datacenter (or
+// process) id in its low 5.
+pub fn decode_snowflake(id: u64) -> DecodedId {
+    IdConfig::new(ConfigPreset::Snowflake, 0).decode(id)
+}
+
+// `(1 << bits) - 1`, except that a field filling the whole word doesn't
+// overflow the shift
+const fn field_mask(bits: u8) -> u64 {
+    if bits >= 64 {
+        u64::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+// The `bits` wide field starting at bit `shift`; whatever lies past bit 63
+// reads as zero
+fn read_field(id: u64, shift: u8, bits: u8) -> u64 {
+    id.checked_shr(shift as u32).unwrap_or(0) & field_mask(bits)
+}
+
+fn place_field(value: u64, shift: u8, bits: u8) -> u64 {
+    (value & field_mask(bits))
+        .checked_shl(shift as u32)
+        .unwrap_or(0)
+}
+
+fn is_zero(bits: &u8) -> bool {
+    *bits == 0
+}
+
+fn default_config_bits() -> u8 {
+    CONFIG_BITS
+}
+
+fn default_inc_bits() -> u8 {
+    INC_BITS
+}
+
+// The bit packing itself needs nothing from std: these take the clock reading
+// and sequence number from the caller, so targets without `std` can build ids
+// from their own timer and counter.
+impl IdConfig {
+    pub fn new(preset: ConfigPreset, epoch: u64) -> Self {
+        let epoch = match preset {
+            ConfigPreset::Custom(epoch, ..) => epoch,
+            _ => epoch,
+        };
+        let (epoch_bits, node_bits, shard_bits) = preset.field_bits();
+        let (config_bits, inc_bits) = preset.fixed_bits();
+
+        Self {
+            epoch,
+            epoch_bits,
+            node_bits,
+            shard_bits,
+            config_id: preset.config_id(),
+            config_bits,
+            inc_bits,
+            shard_checksum_bits: 0,
+            shard2_bits: 0,
+            salt_bits: 0,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        // Ids from different times or nodes would collide
+        if self.epoch_bits == 0 {
+            return Err(ConfigError::ZeroWidthField("epoch_bits"));
+        }
+        if self.node_bits == 0 {
+            return Err(ConfigError::ZeroWidthField("node_bits"));
+        }
+        // `encode` and the 128-bit layout carry 16 bits of sequence
+        if self.inc_bits > MAX_INC_BITS {
+            return Err(ConfigError::SequenceTooWide {
+                inc_bits: self.inc_bits,
+                max: MAX_INC_BITS,
+            });
+        }
+
+        if self.config_id as u64 > field_mask(self.config_bits) {
+            return Err(ConfigError::ConfigIdTooLarge {
+                config_id: self.config_id,
+                config_bits: self.config_bits,
+            });
+        }
+
+        // A checksum needs a shard to cover
+        if self.shard_checksum_bits > 0 && self.shard_bits == 0 {
+            return Err(ConfigError::ZeroWidthField("shard_bits"));
+        }
+        if self.shard2_bits > self.shard_bits {
+            return Err(ConfigError::SecondaryShardTooWide {
+                shard2_bits: self.shard2_bits,
+                shard_bits: self.shard_bits,
+            });
+        }
+        if self.salt_bits > self.node_bits {
+            return Err(ConfigError::SaltTooWide {
+                salt_bits: self.salt_bits,
+                node_bits: self.node_bits,
+            });
+        }
+
+        let total = self.total_bits();
+        if total > 64 {
+            return Err(ConfigError::BitBudgetExceeded {
+                epoch_bits: self.epoch_bits,
+                node_bits: self.node_bits,
+                shard_bits: self.shard_bits,
+                inc_bits: self.inc_bits,
+                config_bits: self.config_bits,
+                shard_checksum_bits: self.shard_checksum_bits,
+                overflow: total - 64,
+            });
+        }
+        Ok(())
+    }
+
+    // The part of the node field below the salt, which holds the node id
+    pub const fn node_id_bits(&self) -> u8 {
+        self.node_bits.saturating_sub(self.salt_bits)
+    }
+
+    // How many of the 64 bits the layout uses, shard checksum included; the
+    // rest are headroom. Unchecked layouts can come to more than 64.
+    pub const fn total_bits(&self) -> u32 {
+        self.config_bits as u32
+            + self.inc_bits as u32
+            + self.shard_bits as u32
+            + self.node_bits as u32
+            + self.epoch_bits as u32
+            + self.shard_checksum_bits as u32
+    }
+
+    // Where each field starts, from the lowest: config, incrementing id,
+    // shard, node, time
+    pub const fn inc_shift(&self) -> u8 {
+        self.config_bits
+    }
+
+    // An unvalidated layout can put a field at or past bit 64. The shifts
+    // saturate rather than overflow, and `encode`/`decode` treat such a field
+    // as holding nothing.
+    pub const fn shard_shift(&self) -> u8 {
+        self.inc_shift().saturating_add(self.inc_bits)
+    }
+
+    pub const fn node_shift(&self) -> u8 {
+        self.shard_shift().saturating_add(self.shard_bits)
+    }
+
+    pub const fn time_shift(&self) -> u8 {
+        self.node_shift().saturating_add(self.node_bits)
+    }
+
+    pub const fn shard_checksum_shift(&self) -> u8 {
+        self.time_shift().saturating_add(self.epoch_bits)
+    }
+
+    pub fn layout(&self) -> Layout {
+        let field = |name, offset, width| LayoutField {
+            name,
+            offset,
+            width,
+        };
+        Layout {
+            fields: [
+                field("config", 0, self.config_bits),
+                field("inc", self.inc_shift(), self.inc_bits),
+                field("shard", self.shard_shift(), self.shard_bits),
+                field("node", self.node_shift(), self.node_bits),
+                field("time", self.time_shift(), self.epoch_bits),
+                field(
+                    "shard_checksum",
+                    self.shard_checksum_shift(),
+                    self.shard_checksum_bits,
+                ),
+            ],
+        }
+    }
+
+    pub fn encode(&self, time_since_epoch: u64, node_id: u32, incrementing_id: u16) -> u64 {
+        // Start with config bits (lowest 3, none for snowflakes)
+        let config_part = place_field(self.config_id as u64, 0, self.config_bits);
+
+        // Incrementing id next (10 bits, 12 for snowflakes)
+        let inc_part = place_field(incrementing_id as u64, self.inc_shift(), self.inc_bits);
+
+        // Node id comes after shard bits, which are 0 for non-sharded configs,
+        // and leaves any salt above it clear
+        let node_part = place_field(node_id as u64, self.node_shift(), self.node_id_bits());
+
+        // Time is highest
+        let time_part = place_field(time_since_epoch, self.time_shift(), self.epoch_bits);
+
+        time_part | node_part | inc_part | config_part
+    }
+
+    // `now_millis` is milliseconds since the UNIX epoch, as from `Clock`.
+    // Keeping incrementing ids unique within a millisecond is up to the caller.
+    pub fn encode_at(
+        &self,
+        now_millis: u64,
+        node_id: u32,
+        incrementing_id: u16,
+    ) -> Result<u64, GenError> {
+        NodeId::checked(node_id as u64, node_count(self.node_id_bits()))?;
+
+        let time_since_epoch =
+            now_millis
+                .checked_sub(self.epoch)
+                .ok_or(GenError::ClockBehindEpoch {
+                    epoch: self.epoch,
+                    now: now_millis,
+                })?;
+        Ok(self.encode(time_since_epoch, node_id, incrementing_id))
+    }
+
+    pub fn decode(&self, id: u64) -> DecodedId {
+        let config_id = read_field(id, 0, self.config_bits) as u8;
+        let incrementing_id = read_field(id, self.inc_shift(), self.inc_bits);
+
+        // Shard bits come after incrementing id, 0 for non-sharded configs
+        let shard_id = read_field(id, self.shard_shift(), self.shard_bits) as u16;
+
+        // Node id now comes after shard bits, without the salt above it
+        let node_id = read_field(id, self.node_shift(), self.node_id_bits());
+
+        // Time comes after node id
+        let time = read_field(id, self.time_shift(), self.epoch_bits);
+
+        DecodedId {
+            time,
+            node_id,
+            shard_id,
+            incrementing_id,
+            config_id,
+        }
+    }
+
+    pub fn derive_sharded_id(&self, original_id: u64, shard: u16) -> Result<u64, ShardError> {
+        if self.shard_bits == 0 {
+            return Err(ShardError::ShardingUnsupported);
+        }
+
+        if shard as u64 > field_mask(self.shard_bits) {
+            return Err(ShardError::ShardOutOfRange {
+                shard,
+                max: 1 << self.shard_bits,
+            });
+        }
+
+        let shard_shift = self.shard_shift();
+        let shard_width = self.shard_bits;
+
+        let shard_mask = place_field(u64::MAX, shard_shift, shard_width);
+
+        let base_id = original_id & !shard_mask;
+
+        let shard_part = place_field(shard as u64, shard_shift, shard_width);
+
+        let checksum_mask = place_field(
+            u64::MAX,
+            self.shard_checksum_shift(),
+            self.shard_checksum_bits,
+        );
+        Ok((base_id & !checksum_mask) | shard_part | self.shard_checksum_part(shard))
+    }
+
+    // Sets both shard levels of a two-level layout: `shard1` goes in the high
+    // part of the shard field and `shard2` in its low `shard2_bits`
+    pub fn derive_two_level_shard(
+        &self,
+        original_id: u64,
+        shard1: u16,
+        shard2: u16,
+    ) -> Result<u64, ShardError> {
+        if self.shard2_bits == 0 || self.shard2_bits >= self.shard_bits {
+            return Err(ShardError::ShardingUnsupported);
+        }
+        let shard1_bits = self.shard_bits - self.shard2_bits;
+        for (shard, bits) in [(shard1, shard1_bits), (shard2, self.shard2_bits)] {
+            if shard as u64 > field_mask(bits) {
+                return Err(ShardError::ShardOutOfRange {
+                    shard,
+                    max: 1 << bits,
+                });
+            }
+        }
+        let shard = (shard1 as u64) << self.shard2_bits | shard2 as u64;
+        self.derive_sharded_id(original_id, shard as u16)
+    }
+
+    // The shard field split into its primary and secondary levels. With a
+    // single level the whole shard is the primary one.
+    pub fn shard_levels(&self, id: u64) -> (u16, u16) {
+        let shard = read_field(id, self.shard_shift(), self.shard_bits);
+        let shard2 = shard & field_mask(self.shard2_bits);
+        let shard1 = shard.checked_shr(self.shard2_bits as u32).unwrap_or(0);
+        (shard1 as u16, shard2 as u16)
+    }
+
+    // The shard folded down to the checksum width by XORing its chunks, so a
+    // single flipped shard bit always flips a checksum bit
+    fn shard_checksum_part(&self, shard: u16) -> u64 {
+        let bits = self.shard_checksum_bits;
+        if bits == 0 {
+            return 0;
+        }
+        let mut rest = shard as u64;
+        let mut checksum = 0;
+        while rest != 0 {
+            checksum ^= rest & field_mask(bits);
+            rest = rest.checked_shr(bits as u32).unwrap_or(0);
+        }
+        place_field(checksum, self.shard_checksum_shift(), bits)
+    }
+
+    // Whether the id's shard checksum matches its shard. Always true for
+    // layouts without a checksum.
+    pub fn verify_shard_checksum(&self, id: u64) -> bool {
+        let checksum_mask = place_field(
+            u64::MAX,
+            self.shard_checksum_shift(),
+            self.shard_checksum_bits,
+        );
+        let shard = read_field(id, self.shard_shift(), self.shard_bits) as u16;
+        id & checksum_mask == self.shard_checksum_part(shard)
+    }
+
+    // Repacks an id from the `source` layout into this one, keeping its
+    // absolute time, node, shard and sequence. The config id becomes this
+    // layout's.
+    pub fn reencode(&self, id: u64, source: &IdConfig) -> Result<u64, ReencodeError> {
+        let decoded = source.decode(id);
+        let timestamp_millis = decoded.timestamp_millis(source.epoch);
+        let time = timestamp_millis
+            .checked_sub(self.epoch)
+            .ok_or(ReencodeError::BeforeEpoch {
+                timestamp_millis,
+                epoch: self.epoch,
+            })?;
+        self.pack(time, &decoded)
+    }
+
+    // Packs decoded fields, with `time` already relative to this layout's
+    // epoch, checking that each fits. The config id is this layout's.
+    fn pack(&self, time: u64, decoded: &DecodedId) -> Result<u64, ReencodeError> {
+        let fits = |field, value, bits| {
+            if value > field_mask(bits) {
+                Err(ReencodeError::FieldTooWide { field, value, bits })
+            } else {
+                Ok(value)
+            }
+        };
+        let time = fits("time", time, self.epoch_bits)?;
+        // Node ids are passed as u32, so anything past 32 bits is unreachable
+        let node_id = fits("node_id", decoded.node_id, self.node_id_bits().min(32))? as u32;
+        let shard_id = fits("shard_id", decoded.shard_id as u64, self.shard_bits)?;
+        let incrementing_id = fits("incrementing_id", decoded.incrementing_id, self.inc_bits)?;
+
+        let shard_part = shard_id << self.shard_shift();
+        Ok(self.encode(time, node_id, incrementing_id as u16)
+            | shard_part
+            | self.shard_checksum_part(shard_id as u16))
+    }
+}
+
+#[cfg(feature = "std")]
+impl IdGenerator {
+    // The config and sequence widths of the ShortEpochMaxNodes, ShardedConfig
+    // and ShardedConfigWide presets, and the builder's defaults. Snowflake and
+    // custom layouts can differ, see `layout()`.
+    pub const CONFIG_BITS: u8 = CONFIG_BITS;
+    pub const INC_BITS: u8 = INC_BITS;
+
+    pub fn new(preset: ConfigPreset, epoch: u64) -> Self {
+        Self::with_layout(IdConfig::new(preset, epoch))
+    }
+
+    fn with_layout(config: IdConfig) -> Self {
+        Self {
+            epoch: config.epoch,
+            epoch_bits: config.epoch_bits,
+            node_bits: config.node_bits,
+            shard_bits: config.shard_bits,
+            shard_checksum_bits: config.shard_checksum_bits,
+            shard2_bits: config.shard2_bits,
+            max_nodes: node_count(config.node_id_bits()),
+            config_id: config.config_id,
+            config_bits: config.config_bits,
+            inc_bits: config.inc_bits,
+            sequences: Sequences::Shared(Sequence::new()),
+            clock: Arc::new(SystemClock),
+            metrics: Arc::new(NoopMetrics),
+            monotonic_tolerance: None,
+            fixed_node: NodeId(0),
+            exhaustion_threshold: DEFAULT_EXHAUSTION_THRESHOLD,
+            rollover_strategy: RolloverStrategy::Spin,
+            reset_sequence_each_ms: false,
+            deterministic: false,
+            on_exhaustion: None,
+            #[cfg(test)]
+            rollover_wait_hook: None,
+            salt_bits: config.salt_bits,
+            salt: draw_salt(config.salt_bits),
+            #[cfg(feature = "secure")]
+            random_window: Mutex::new(RandomWindow::default()),
+            backfill: Mutex::default(),
+        }
+    }
+
+    pub fn with_clock<C: Clock + 'static>(preset: ConfigPreset, epoch: u64, clock: C) -> Self {
+        let mut gen = Self::new(preset, epoch);
+        gen.clock = Arc::new(clock);
+        gen
+    }
+
+    pub fn with_metrics<M: Metrics + 'static>(
+        preset: ConfigPreset,
+        epoch: u64,
+        metrics: M,
+    ) -> Self {
+        let mut gen = Self::new(preset, epoch);
+        gen.metrics = Arc::new(metrics);
+        gen
+    }
+
+    // Leases a node id that fits the layout and fixes the generator to it, so
+    // `next` issues for it. The lease has to be renewed with the allocator
+    // for as long as the generator is in use.
+    #[cfg(feature = "cluster")]
+    pub fn new_with_allocator(
+        preset: ConfigPreset,
+        epoch: u64,
+        allocator: &NodeIdAllocator,
+    ) -> Result<(Self, NodeLease), AllocError> {
+        let mut gen = Self::new(preset, epoch);
+        let lease = allocator.allocate(gen.max_nodes)?;
+        // Leases are only handed out below `max_nodes`
+        gen.fixed_node = NodeId(lease.node_id);
+        Ok((gen, lease))
+    }
+
+    // For deployments with a single node id, which `next` then issues for
+    pub fn with_fixed_node(
+        preset: ConfigPreset,
+        epoch: u64,
+        node_id: u64,
+    ) -> Result<Self, GenError> {
+        let mut gen = Self::new(preset, epoch);
+        gen.fixed_node = gen.node_id(node_id)?;
+        Ok(gen)
+    }
+
+    /// Creates a generator that keeps a separate sequence counter for every
+    /// node, so each node id gets its own 1024 ids per millisecond instead of
+    /// sharing one budget. The counters are allocated up front, one per node
+    /// id in `max_nodes`, at 32 bytes each: 512 KiB for the 14-bit presets
+    /// and 2 MiB for a 16-bit node field.
+    pub fn with_per_node_sequences(preset: ConfigPreset, epoch: u64) -> Self {
+        let mut gen = Self::new(preset, epoch);
+        gen.sequences = Sequences::PerNode((0..gen.max_nodes).map(|_| Sequence::new()).collect());
+        gen
+    }
+
+    // Resumes the counter from a value saved with `current_sequence`, so a
+    // restarted process doesn't reissue the low sequence numbers it just used
+    pub fn with_initial_sequence(preset: ConfigPreset, epoch: u64, seq: u64) -> Self {
+        let mut gen = Self::new(preset, epoch);
+        gen.sequences = Sequences::Shared(Sequence::starting_at(seq));
+        gen
+    }
+
+    // For snapshot tests: ids start at `fixed_millis` and sequence value
+    // `start_seq`, and every id after that follows from the ones before, so
+    // two generators built with the same arguments issue the same ids in the
+    // same order. Time only moves on, one millisecond at a time, when a
+    // millisecond's sequence is spent.
+    pub fn deterministic(
+        preset: ConfigPreset,
+        epoch: u64,
+        fixed_millis: u64,
+        start_seq: u64,
+    ) -> Self {
+        let mut gen = Self::with_initial_sequence(preset, epoch, start_seq);
+        gen.clock = Arc::new(FixedClock(fixed_millis));
+        gen.deterministic = true;
+        gen
+    }
+
+    pub fn from_config(config: IdConfig) -> Result<Self, ConfigError> {
+        config.validate()?;
+        Ok(Self::with_layout(config))
+    }
+
+    // Builds from GEN_ID_EPOCH, GEN_ID_EPOCH_BITS, GEN_ID_NODE_BITS,
+    // GEN_ID_SHARD_BITS and GEN_ID_CONFIG_ID. Unset ones take the builder's
+    // defaults, and the result is validated like any built layout.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::from_vars(std::env::var)
+    }
+
+    // `from_env` with the variables looked up through `lookup`, so the
+    // parsing can be tested without touching the process environment
+    fn from_vars<F>(lookup: F) -> Result<Self, ConfigError>
+    where
+        F: Fn(&'static str) -> Result<String, std::env::VarError>,
+    {
+        fn var<F, T>(lookup: &F, name: &'static str, default: T) -> Result<T, ConfigError>
+        where
+            F: Fn(&'static str) -> Result<String, std::env::VarError>,
+            T: FromStr,
+        {
+            match lookup(name) {
+                Ok(value) => value
+                    .trim()
+                    .parse()
+                    .map_err(|_| ConfigError::InvalidEnvVar(name)),
+                Err(std::env::VarError::NotPresent) => Ok(default),
+                Err(std::env::VarError::NotUnicode(_)) => Err(ConfigError::InvalidEnvVar(name)),
+            }
+        }
+
+        let defaults = IdGeneratorBuilder::default();
+        IdGeneratorBuilder::new()
+            .epoch(var(&lookup, "GEN_ID_EPOCH", defaults.epoch)?)
+            .epoch_bits(var(&lookup, "GEN_ID_EPOCH_BITS", defaults.epoch_bits)?)
+            .node_bits(var(&lookup, "GEN_ID_NODE_BITS", defaults.node_bits)?)
+            .shard_bits(var(&lookup, "GEN_ID_SHARD_BITS", defaults.shard_bits)?)
+            .config_id(var(&lookup, "GEN_ID_CONFIG_ID", defaults.config_id)?)
+            .build()
+    }
+
+    pub fn total_bits(&self) -> u32 {
+        IdConfig::from(self).total_bits()
+    }
+
+    // Largest offset from the epoch the time field can hold
+    pub fn max_timestamp_millis(&self) -> u64 {
+        field_mask(self.epoch_bits)
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub fn epoch_bits(&self) -> u8 {
+        self.epoch_bits
+    }
+
+    pub fn node_bits(&self) -> u8 {
+        self.node_bits
+    }
+
+    pub fn shard_bits(&self) -> u8 {
+        self.shard_bits
+    }
+
+    // Node ids below this are accepted; at most 2^31, since node counts are u32
+    pub fn max_nodes(&self) -> u32 {
+        self.max_nodes
+    }
+
+    pub fn config_id(&self) -> u8 {
+        self.config_id
+    }
+
+    // The config id in one of this layout's ids, 0 when it has no config field
+    pub fn config_id_of(&self, id: u64) -> u8 {
+        read_field(id, 0, self.config_bits) as u8
+    }
+
+    // A node id picked by hashing `key`, so ids issued for one entity with
+    // `next_id(node_for_key(key))` all come from the same node. The hash is
+    // stable across builds and platforms, but the mapping changes with the
+    // number of nodes.
+    pub fn node_for_key(&self, key: &[u8]) -> NodeId {
+        NodeId((fnv1a(key) % self.max_nodes as u64) as u32)
+    }
+
+    // Whether the shard methods can succeed; without shard bits
+    // `derive_sharded_id` panics
+    pub fn supports_sharding(&self) -> bool {
+        self.shard_bits > 0
+    }
+
+    pub fn max_node_count(&self) -> u64 {
+        // Saturates for unchecked layouts with a node field 64 bits wide
+        1u64.checked_shl(self.node_bits as u32).unwrap_or(u64::MAX)
+    }
+
+    // Layouts without shard bits have a single implicit shard 0
+    pub fn max_shard_count(&self) -> u32 {
+        1u32.checked_shl(self.shard_bits as u32).unwrap_or(u32::MAX)
+    }
+
+    // What the layout allows at millisecond resolution. A single generator
+    // with a shared sequence tops out at the per-node figure across all the
+    // nodes it serves; the total needs a generator or a sequence per node.
+    // Wide fields in unchecked or builder layouts can outgrow a u64, so these
+    // saturate rather than overflow
+    pub fn max_ids_per_second_per_node(&self) -> u64 {
+        self.ids_per_millis().saturating_mul(1000)
+    }
+
+    pub fn max_ids_per_second_total(&self) -> u64 {
+        self.max_ids_per_second_per_node()
+            .saturating_mul(self.max_node_count())
+    }
+
+    fn ids_per_millis(&self) -> u64 {
+        1u64.checked_shl(self.inc_bits as u32).unwrap_or(u64::MAX)
+    }
+
+    // The most ids one node can have from `start_ms` up to, but not including,
+    // `end_ms`, both unix millis. Only the part of the window the time field
+    // covers counts, from the epoch to the last millisecond it holds.
+    pub fn capacity_in_window(&self, start_ms: u64, end_ms: u64) -> u64 {
+        let first = start_ms.max(self.epoch);
+        let end = end_ms.min(
+            self.epoch
+                .saturating_add(self.max_timestamp_millis())
+                .saturating_add(1),
+        );
+        end.saturating_sub(first)
+            .saturating_mul(self.ids_per_millis())
+    }
+
+    // The last instant the time field can represent; after it ids wrap
+    // around and collide with ones issued right after the epoch
+    pub fn epoch_exhaustion_date(&self) -> SystemTime {
+        // A 64-bit time field from a late epoch runs past u64 milliseconds
+        UNIX_EPOCH + Duration::from_millis(self.epoch.saturating_add(self.max_timestamp_millis()))
+    }
+
+    pub fn builder() -> IdGeneratorBuilder {
+        IdGeneratorBuilder::new()
+    }
+
+    // Checks the layout, and that the epoch isn't after the current time, so
+    // a mistyped epoch fails here rather than on the first `next_id`
+    pub fn try_new(preset: ConfigPreset, epoch: u64) -> Result<Self, ConfigError> {
+        let gen = Self::try_new_trusted(preset, epoch)?;
+        // A Custom preset carries its own epoch, which is the one ids use
+        let now = gen.clock.now_millis();
+        if gen.epoch > now {
+            return Err(ConfigError::EpochInFuture {
+                epoch: gen.epoch,
+                now,
+            });
+        }
+        Ok(gen)
+    }
+
+    // `try_new` without the epoch check, for deterministic tests and layouts
+    // whose epoch is meant to be ahead of the system clock
+    pub fn try_new_trusted(preset: ConfigPreset, epoch: u64) -> Result<Self, ConfigError> {
+        let config = IdConfig::new(preset, epoch);
+        config.validate()?;
+        Ok(Self::with_layout(config))
+    }
+
+    pub fn derive_sharded_id(&self, original_id: u64, shard: u16) -> u64 {
+        self.try_derive_sharded_id(original_id, shard)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_derive_sharded_id(&self, original_id: u64, shard: u16) -> Result<u64, ShardError> {
+        IdConfig::from(self).derive_sharded_id(original_id, shard)
+    }
+
+    // Same as `derive_sharded_id(next_id(node_id), shard)`, but the shard is
+    // checked before a sequence value is spent. Clock and node id errors panic
+    // as they do in `next_id`.
+    pub fn next_sharded_id(&self, node_id: NodeId, shard: u16) -> Result<u64, ShardError> {
+        let shard_part = IdConfig::from(self).derive_sharded_id(0, shard)?;
+        Ok(self.next_id(node_id) | shard_part)
+    }
+
+    // Moving a record between shards only touches the shard field, so this is
+    // the same operation as deriving a sharded id from the original
+    pub fn reshard(&self, id: u64, new_shard: u16) -> Result<u64, ShardError> {
+        self.try_derive_sharded_id(id, new_shard)
+    }
+
+    pub fn derive_two_level_shard(
+        &self,
+        original_id: u64,
+        shard1: u16,
+        shard2: u16,
+    ) -> Result<u64, ShardError> {
+        IdConfig::from(self).derive_two_level_shard(original_id, shard1, shard2)
+    }
+
+    pub fn shard_levels(&self, id: u64) -> (u16, u16) {
+        IdConfig::from(self).shard_levels(id)
+    }
+
+    pub fn shard_of(&self, id: u64) -> u16 {
+        read_field(id, IdConfig::from(self).shard_shift(), self.shard_bits) as u16
+    }
+
+    // With a salt, the node id comes back without it; see `salt_of`
+    pub fn decode_id(&self, id: u64) -> DecodedId {
+        IdConfig::from(self).decode(id)
+    }
+
+    // Decodes as if this layout had `shard_bits` of shard, for ids from a
+    // source whose shard width differs from ours. Every field above the shard
+    // moves with it; the time field keeps its width, so reading a source with
+    // a narrower time field still works as long as the bits above it are 0.
+    pub fn decode_id_with_shard_bits(&self, id: u64, shard_bits: u8) -> DecodedId {
+        let config = IdConfig {
+            shard_bits,
+            ..IdConfig::from(self)
+        };
+        config.decode(id)
+    }
+
+    // Just the node field, for routing by node without unpacking the rest
+    pub fn node_id_of(&self, id: u64) -> u64 {
+        read_field(
+            id,
+            IdConfig::from(self).node_shift(),
+            self.node_bits - self.salt_bits,
+        )
+    }
+
+    pub fn decode_many(&self, ids: &[u64]) -> Vec<DecodedId> {
+        ids.iter().map(|&id| self.decode_id(id)).collect()
+    }
+
+    pub fn salt(&self) -> u64 {
+        self.salt
+    }
+
+    pub fn salt_of(&self, id: u64) -> u64 {
+        read_field(id, self.salt_shift(), self.salt_bits)
+    }
+
+    fn salt_shift(&self) -> u8 {
+        IdConfig::from(self).time_shift() - self.salt_bits
+    }
+
+    pub fn cmp_by_time(&self, a: u64, b: u64) -> std::cmp::Ordering {
+        self.decode_id(a).time.cmp(&self.decode_id(b).time)
+    }
+
+    pub fn layout(&self) -> Layout {
+        IdConfig::from(self).layout()
+    }
+
+    // The inverse of `decode_id`: packs all five fields, the config id
+    // included, and puts the salt back. Each has to fit its field.
+    pub fn encode(&self, decoded: &DecodedId) -> Result<u64, ReencodeError> {
+        let fits = |field, value, bits| {
+            if value > field_mask(bits) {
+                Err(ReencodeError::FieldTooWide { field, value, bits })
+            } else {
+                Ok(())
+            }
+        };
+        fits("config_id", decoded.config_id as u64, self.config_bits)?;
+        fits("node_id", decoded.node_id, self.node_bits - self.salt_bits)?;
+
+        let config = IdConfig {
+            config_id: decoded.config_id,
+            ..IdConfig::from(self)
+        };
+        Ok(config.pack(decoded.time, decoded)?
+            | place_field(self.salt, self.salt_shift(), self.salt_bits))
+    }
+
+    // Rewrites the time field so the id keeps its absolute time when read
+    // against `new_epoch` instead of this generator's epoch. Every other field
+    // is kept as it is.
+    pub fn rebase_epoch(&self, id: u64, new_epoch: u64) -> Result<u64, GenError> {
+        let config = IdConfig::from(self);
+        let timestamp_millis = config.decode(id).timestamp_millis(self.epoch);
+        let time = timestamp_millis
+            .checked_sub(new_epoch)
+            .ok_or(GenError::ClockBehindEpoch {
+                epoch: new_epoch,
+                now: timestamp_millis,
+            })?;
+        if time > self.max_timestamp_millis() {
+            return Err(GenError::TimestampOutOfRange {
+                timestamp_millis,
+                max: new_epoch.saturating_add(self.max_timestamp_millis()),
+            });
+        }
+
+        let time_mask = place_field(u64::MAX, config.time_shift(), self.epoch_bits);
+        Ok(id & !time_mask | place_field(time, config.time_shift(), self.epoch_bits))
+    }
+
+    // Puts this generator's salt in, as `encode` does
+    pub fn reencode(&self, id: u64, source: &IdGenerator) -> Result<u64, ReencodeError> {
+        Ok(IdConfig::from(self).reencode(id, &IdConfig::from(source))?
+            | place_field(self.salt, self.salt_shift(), self.salt_bits))
+    }
+
+    // Ids from the same millisecond and node drew on the same sequence
+    // window, so a burst of these is what exhausts it
+    pub fn same_generation_window(&self, a: u64, b: u64) -> bool {
+        let (a, b) = (self.decode_id(a), self.decode_id(b));
+        a.time == b.time && a.node_id == b.node_id
+    }
+
+    pub fn id_to_bytes(&self, id: u64) -> [u8; DecodedId::BYTES] {
+        self.decode_id(id).to_bytes()
+    }
+
+    // The inverse of `id_to_bytes`. Fields that don't fit this layout are an
+    // error, and the config id is always this layout's.
+    pub fn id_from_bytes(&self, bytes: &[u8; DecodedId::BYTES]) -> Result<u64, ReencodeError> {
+        let decoded = DecodedId::from_bytes(bytes);
+        IdConfig::from(self).pack(decoded.time, &decoded)
+    }
+
+    // True when no id from this generator can equal one from `other`: both
+    // carry a config field and their config ids differ in the bits the two
+    // fields share. Generators with the same config id, or without config
+    // bits, can still be disjoint by keeping to separate node ids, but nothing
+    // here can check that, so they count as overlapping.
+    pub fn spaces_disjoint(&self, other: &IdGenerator) -> bool {
+        let shared_bits = self.config_bits.min(other.config_bits);
+        let mask = field_mask(shared_bits) as u8;
+        shared_bits > 0 && self.config_id & mask != other.config_id & mask
+    }
+
+    pub fn try_decode_id(&self, id: u64) -> Result<DecodedId, DecodeError> {
+        let mask = field_mask(self.config_bits);
+        let expected = (self.config_id as u64 & mask) as u8;
+        let found = (id & mask) as u8;
+        if found != expected {
+            return Err(DecodeError::ConfigMismatch { expected, found });
+        }
+        if !self.verify_shard_checksum(id) {
+            return Err(DecodeError::ShardChecksumMismatch);
+        }
+        Ok(self.decode_id(id))
+    }
+
+    pub fn verify_shard_checksum(&self, id: u64) -> bool {
+        IdConfig::from(self).verify_shard_checksum(id)
+    }
+
+    // For ids from untrusted clients: checks the id could have come from this
+    // generator's layout, and that its time is no later than `now`, in unix
+    // millis, plus `max_future_skew_ms`. It can't tell whether the id was
+    // actually issued.
+    pub fn validate(
+        &self,
+        id: u64,
+        now: u64,
+        max_future_skew_ms: u64,
+    ) -> Result<(), ValidationError> {
+        let config = IdConfig::from(self);
+        let mask = field_mask(self.config_bits);
+        let expected = (self.config_id as u64 & mask) as u8;
+        let found = (id & mask) as u8;
+        if found != expected {
+            return Err(ValidationError::ConfigMismatch { expected, found });
+        }
+        let used_bits = config
+            .shard_checksum_shift()
+            .saturating_add(self.shard_checksum_bits);
+        if read_field(id, used_bits, 64) != 0 {
+            return Err(ValidationError::UnusedBitsSet);
+        }
+        let decoded = self.decode_id(id);
+        if decoded.node_id >= self.max_nodes as u64 {
+            return Err(ValidationError::NodeOutOfRange {
+                node_id: decoded.node_id,
+                max_nodes: self.max_nodes,
+            });
+        }
+        // Shards are u16s, so a wider field can hold ones no call derives
+        let shard = read_field(id, config.shard_shift(), self.shard_bits);
+        let max_shards = self.max_shard_count().min(1 << 16);
+        if shard >= max_shards as u64 {
+            return Err(ValidationError::ShardOutOfRange {
+                shard,
+                max: max_shards,
+            });
+        }
+        if !self.verify_shard_checksum(id) {
+            return Err(ValidationError::ShardChecksumMismatch);
+        }
+        let timestamp_millis = decoded.timestamp_millis(self.epoch);
+        let limit = now.saturating_add(max_future_skew_ms);
+        if timestamp_millis > limit {
+            return Err(ValidationError::TimeInFuture {
+                timestamp_millis,
+                limit,
+            });
+        }
+        Ok(())
+    }
+
+    // Checks the layout, then packs the smallest and largest value of every
+    // field and makes sure each decodes back. Meant for service startup, to
+    // catch a custom layout built with the unchecked `new` before it issues
+    // ids. The sequence counter isn't touched.
+    pub fn self_test(&self) -> Result<(), SelfTestError> {
+        let config = IdConfig::from(self);
+        config.validate().map_err(SelfTestError::Layout)?;
+
+        let max_node = self.max_nodes - 1;
+        let max_inc = field_mask(self.inc_bits) as u16;
+        let max_shard = field_mask(self.shard_bits) as u16;
+        let cases = [
+            (0, 0, 0, 0),
+            (self.max_timestamp_millis(), max_node, max_inc, max_shard),
+            (self.max_timestamp_millis(), 0, 0, 0),
+            (0, max_node, 0, 0),
+            (0, 0, max_inc, 0),
+            (0, 0, 0, max_shard),
+        ];
+
+        for (time, node_id, incrementing_id, shard_id) in cases {
+            let mut id = config.encode(time, node_id, incrementing_id);
+            // Fails without shard bits, where the shard is always 0 anyway
+            if let Ok(sharded) = config.derive_sharded_id(id, shard_id) {
+                id = sharded;
+            }
+
+            let decoded = self.decode_id(id);
+            let fields = [
+                ("time", time, decoded.time),
+                ("node_id", node_id as u64, decoded.node_id),
+                ("shard_id", shard_id as u64, decoded.shard_id as u64),
+                (
+                    "incrementing_id",
+                    incrementing_id as u64,
+                    decoded.incrementing_id,
+                ),
+                ("config_id", self.config_id as u64, decoded.config_id as u64),
+            ];
+            for (field, expected, found) in fields {
+                if expected != found {
+                    return Err(SelfTestError::RoundTrip {
+                        field,
+                        expected,
+                        found,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn time_since_epoch(&self) -> Result<u64, GenError> {
+        let millis = self.clock.now_millis();
+        millis
+            .checked_sub(self.epoch)
+            .ok_or(GenError::ClockBehindEpoch {
+                epoch: self.epoch,
+                now: millis,
+            })
+    }
+
+    fn generate_id(&self, time_since_epoch: u64, node_id: u32, counter: u64) -> u64 {
+        self.metrics.on_id_generated();
+        self.pack_id(time_since_epoch, node_id, counter)
+    }
+
+    fn pack_id(&self, time_since_epoch: u64, node_id: u32, counter: u64) -> u64 {
+        // The sequence is at most 16 bits wide, so the truncation only drops
+        // bits `encode` would mask off anyway
+        IdConfig::from(self).encode(time_since_epoch, node_id, counter as u16)
+            | place_field(self.salt, self.salt_shift(), self.salt_bits)
+    }
+
+    // Reserves up to `wanted` consecutive counter values and pairs them with a
+    // millisecond in which they have not been used yet, returning the
+    // millisecond, the first value and how many were granted. Each millisecond
+    // window covers the 1 << inc_bits counter values following the one that
+    // opened it; once those are spent we wait, as the rollover strategy says,
+    // until the clock moves on and open a new window.
+    fn reserve_sequence(
+        &self,
+        sequence: &Sequence,
+        wanted: u64,
+    ) -> Result<(u64, u64, u64), GenError> {
+        let mut wait_started = None;
+        loop {
+            match self.try_reserve_sequence(sequence, wanted)? {
+                Some(reserved) => {
+                    self.report_rollover_wait(wait_started);
+                    return Ok(reserved);
+                }
+                None => {
+                    self.start_rollover_wait(&mut wait_started, || {
+                        sequence.lock().report_exhaustion(self.inc_bits)
+                    });
+                    self.rollover_wait();
+                }
+            }
+        }
+    }
+
+    // Notes when a wait for the next millisecond began, and tells the
+    // exhaustion callback if `first_to_report` says no other waiter on the
+    // same spent millisecond already has
+    fn start_rollover_wait(
+        &self,
+        wait_started: &mut Option<u64>,
+        first_to_report: impl FnOnce() -> bool,
+    ) {
+        if wait_started.is_none() {
+            *wait_started = Some(self.clock.now_millis());
+            if let Some(on_exhaustion) = &self.on_exhaustion {
+                if first_to_report() {
+                    on_exhaustion();
+                }
+            }
+        }
+    }
+
+    fn rollover_wait(&self) {
+        #[cfg(test)]
+        if let Some(hook) = &self.rollover_wait_hook {
+            return hook(self.rollover_strategy);
+        }
+        self.rollover_strategy.wait();
+    }
+
+    // The wait is timed with the generator's own clock, which is all it is
+    // waiting on, so a mock clock gives repeatable durations
+    fn report_rollover_wait(&self, wait_started: Option<u64>) {
+        if let Some(started) = wait_started {
+            let waited = self.clock.now_millis().saturating_sub(started);
+            self.metrics.on_rollover_wait(Duration::from_millis(waited));
+        }
+    }
+
+    // A single attempt at `reserve_sequence`, giving `None` when the current
+    // millisecond has no values left
+    fn try_reserve_sequence(
+        &self,
+        sequence: &Sequence,
+        wanted: u64,
+    ) -> Result<Option<(u64, u64, u64)>, GenError> {
+        sequence.try_reserve(|state| self.now_for(state), self.window_policy(), wanted)
+    }
+
+    // The offset from the epoch a sequence issues its next ids at. A
+    // deterministic generator takes it from the sequence, so nothing else
+    // that reads the clock can move its time along.
+    fn now_for(&self, state: &SequenceState) -> Result<u64, GenError> {
+        let now = self.time_since_epoch()?;
+        if self.deterministic {
+            return Ok(state.deterministic_now(now, self.inc_bits));
+        }
+        Ok(now)
+    }
+
+    fn window_policy(&self) -> WindowPolicy {
+        WindowPolicy {
+            inc_bits: self.inc_bits,
+            monotonic_tolerance: self.monotonic_tolerance,
+            reset_each_ms: self.reset_sequence_each_ms,
+        }
+    }
+
+    // Checks a raw node id against the node bits, for passing to `next_id`
+    // and friends
+    pub fn node_id(&self, raw: u64) -> Result<NodeId, GenError> {
+        NodeId::checked(raw, self.max_nodes)
+    }
+
+    fn sequence_for(&self, node_id: u32) -> Result<&Sequence, GenError> {
+        NodeId::checked(node_id as u64, self.max_nodes)?;
+        Ok(match &self.sequences {
+            Sequences::Shared(sequence) => sequence,
+            Sequences::PerNode(sequences) => &sequences[node_id as usize],
+        })
+    }
+
+    // The sequence the no-argument getters report on: node 0's when each
+    // node has its own
+    fn first_sequence(&self) -> &Sequence {
+        match &self.sequences {
+            Sequences::Shared(sequence) => sequence,
+            Sequences::PerNode(sequences) => &sequences[0],
+        }
+    }
+
+    // The raw counter value the next id will take its sequence from. With
+    // per-node sequences this is node 0's counter.
+    pub fn current_sequence(&self) -> u64 {
+        self.first_sequence().lock().next_id
+    }
+
+    // Takes `n` values from the counter without making ids, for callers that
+    // pack their own with a time source of their own. Returns the first and
+    // last sequence value of the range, inclusive; when it wraps past the top
+    // of the sequence field the last is below the first. Later ids skip the
+    // reserved values, and they count against the current millisecond as
+    // live ids do. With per-node sequences they come from node 0's
+    // counter. Panics if `n` is 0 or more than the sequence field holds.
+    pub fn reserve_sequences(&self, n: u16) -> (u16, u16) {
+        let window_size: u64 = 1 << self.inc_bits;
+        assert!(
+            n > 0 && n as u64 <= window_size,
+            "Can't reserve {} sequence numbers, the sequence field holds {}",
+            n,
+            window_size
+        );
+        let mask = window_size - 1;
+        let mut state = self.first_sequence().lock();
+        let first = state.next_id;
+        state.next_id = first.wrapping_add(n as u64);
+        let last = first.wrapping_add(n as u64 - 1);
+        ((first & mask) as u16, (last & mask) as u16)
+    }
+
+    // How many more ids the current millisecond can hand out before `next_id`
+    // has to wait for the clock. Once the clock has moved past the window's
+    // millisecond the next id opens a fresh one, so all of it counts as left.
+    pub fn remaining_in_window(&self) -> u64 {
+        let window_size: u64 = 1 << self.inc_bits;
+        let state = self.first_sequence().lock();
+        let moved_on = self
+            .time_since_epoch()
+            .is_ok_and(|now| now > state.window_millis);
+        if moved_on {
+            return window_size;
+        }
+        // Values taken by `reserve_sequences` can take the count past the window
+        window_size.saturating_sub(state.next_id.wrapping_sub(state.window_start))
+    }
+
+    // Stamps an id with `millis`, milliseconds since the UNIX epoch, instead of
+    // the clock, for backfilling historical records. Milliseconds before live
+    // ids started (before the clock, if none have been issued yet) count
+    // their own sequence values, kept for as long as the generator lives.
+    // The live window's millisecond takes values from the live sequence like
+    // `next_id` does; anything between or after may clash with live ids and
+    // fails with `BackfillOverlapsLive`.
+    pub fn next_id_at(&self, node_id: NodeId, millis: u64) -> Result<u64, GenError> {
+        let node_id = node_id.get();
+        let sequence = self.sequence_for(node_id)?;
+        let time_since_epoch = self.offset_of(millis)?;
+
+        let mut state = sequence.lock();
+        let live_since = match state.live_since {
+            Some(live_since) => live_since,
+            None => self.time_since_epoch()?,
+        };
+        let incrementing_id = if time_since_epoch < live_since {
+            let mut backfill = self.backfill.lock().unwrap_or_else(PoisonError::into_inner);
+            let used = backfill.entry((node_id, time_since_epoch)).or_insert(0);
+            if *used > field_mask(self.inc_bits) {
+                return Err(GenError::BackfillExhausted {
+                    timestamp_millis: millis,
+                });
+            }
+            *used += 1;
+            *used - 1
+        } else if state.live_since.is_some() && time_since_epoch == state.window_millis {
+            match state.reserve(time_since_epoch, self.window_policy(), 1)? {
+                Some((_, first, _)) => first,
+                None => {
+                    return Err(GenError::BackfillExhausted {
+                        timestamp_millis: millis,
+                    })
+                }
+            }
+        } else {
+            return Err(GenError::BackfillOverlapsLive {
+                timestamp_millis: millis,
+                live_since: self.epoch.saturating_add(live_since),
+            });
+        };
+        drop(state);
+        Ok(self.generate_id(time_since_epoch, node_id, incrementing_id))
+    }
+
+    // `millis`, a Unix timestamp, as an offset the time field can hold
+    fn offset_of(&self, millis: u64) -> Result<u64, GenError> {
+        let time_since_epoch =
+            millis
+                .checked_sub(self.epoch)
+                .ok_or(GenError::ClockBehindEpoch {
+                    epoch: self.epoch,
+                    now: millis,
+                })?;
+        if time_since_epoch > self.max_timestamp_millis() {
+            return Err(GenError::TimestampOutOfRange {
+                timestamp_millis: millis,
+                max: self.epoch.saturating_add(self.max_timestamp_millis()),
+            });
+        }
+        Ok(time_since_epoch)
+    }
+
+    // The smallest and largest id `node_id` can have in the millisecond
+    // `millis`, for range queries like `WHERE id BETWEEN min AND max`. Ids
+    // grow with time first, so a range spanning several milliseconds goes
+    // from the first one's minimum to the last one's maximum. A shard
+    // checksum sits above the time field and doesn't grow with the shard, so
+    // these bounds take it as all zeros and all ones: they still hold every
+    // id of the millisecond, but also ones from others.
+    pub fn min_id_for_time(&self, node_id: NodeId, millis: u64) -> Result<u64, GenError> {
+        self.id_for_time(node_id, millis, 0, 0, 0)
+    }
+
+    pub fn max_id_for_time(&self, node_id: NodeId, millis: u64) -> Result<u64, GenError> {
+        self.id_for_time(
+            node_id,
+            millis,
+            field_mask(self.shard_bits) as u16,
+            field_mask(self.inc_bits),
+            field_mask(self.shard_checksum_bits),
+        )
+    }
+
+    // The first id `node_id` can have: at the instant of the epoch, with
+    // sequence 0 and shard 0. Panics if the node id is out of range, as
+    // `next_id` does.
+    pub fn genesis_id(&self, node_id: NodeId) -> u64 {
+        self.min_id_for_time(node_id, self.epoch)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn id_for_time(
+        &self,
+        node_id: NodeId,
+        millis: u64,
+        shard_id: u16,
+        incrementing_id: u64,
+        shard_checksum: u64,
+    ) -> Result<u64, GenError> {
+        let node_id = NodeId::checked(node_id.get() as u64, self.max_nodes)?.get();
+        let time = self.offset_of(millis)?;
+        let config = IdConfig::from(self);
+        Ok(self.pack_id(time, node_id, incrementing_id)
+            | place_field(shard_id as u64, config.shard_shift(), self.shard_bits)
+            | place_field(
+                shard_checksum,
+                config.shard_checksum_shift(),
+                self.shard_checksum_bits,
+            ))
+    }
+
+    // The latest millisecond, as a Unix timestamp, that any id has been issued
+    // in. It only moves forward, so in monotonic mode it stays ahead of a clock
+    // that has stepped back. Before the first id it is the epoch.
+    pub fn last_timestamp(&self) -> u64 {
+        let window_millis = match &self.sequences {
+            Sequences::Shared(sequence) => sequence.lock().window_millis,
+            Sequences::PerNode(sequences) => sequences
+                .iter()
+                .map(|sequence| sequence.lock().window_millis)
+                .max()
+                .unwrap_or(0),
+        };
+        self.epoch + window_millis
+    }
+
+    pub fn try_next_id(&self, node_id: NodeId) -> Result<u64, GenError> {
+        let node_id = node_id.get();
+        let sequence = self.sequence_for(node_id)?;
+        let (time_since_epoch, incrementing_id, _) = self.reserve_sequence(sequence, 1)?;
+        Ok(self.generate_id(time_since_epoch, node_id, incrementing_id))
+    }
+
+    pub fn next_id(&self, node_id: NodeId) -> u64 {
+        self.try_next_id(node_id)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_next_gen_id(&self, node_id: NodeId) -> Result<GenId, GenError> {
+        self.try_next_id(node_id).map(GenId)
+    }
+
+    pub fn next_gen_id(&self, node_id: NodeId) -> GenId {
+        GenId(self.next_id(node_id))
+    }
+
+    // How much of the time field the clock has used up, from 0.0 at the epoch
+    // to 1.0 at the last millisecond it can hold. Past that, ids wrap around
+    // and collide with the earliest ones. A clock behind the epoch reads 0.0.
+    pub fn exhaustion_ratio(&self) -> f64 {
+        let elapsed = self.time_since_epoch().unwrap_or(0);
+        elapsed as f64 / self.max_timestamp_millis() as f64
+    }
+
+    // `try_next_id` that fails with `GenError::EpochExhausted` once the
+    // exhaustion ratio passes the builder's `exhaustion_threshold`, 0.9 unless
+    // set, leaving time to move to a new epoch before ids wrap
+    pub fn try_next_id_guarded(&self, node_id: NodeId) -> Result<u64, GenError> {
+        if self.exhaustion_ratio() > self.exhaustion_threshold {
+            return Err(GenError::EpochExhausted {
+                elapsed: self.time_since_epoch()?,
+                max: self.max_timestamp_millis(),
+            });
+        }
+        self.try_next_id(node_id)
+    }
+
+    // `try_next_id` for the node set with `with_fixed_node`
+    pub fn try_next(&self) -> Result<u64, GenError> {
+        self.try_next_id(self.fixed_node)
+    }
+
+    pub fn next(&self) -> u64 {
+        self.next_id(self.fixed_node)
+    }
+
+    // Like `try_next_id`, but the sequence field is drawn from the OS random
+    // source, so ids within a millisecond can't be guessed from one another.
+    // The time still never goes backwards, and a value already drawn this
+    // millisecond is drawn again. These draws are tracked apart from the
+    // counter behind `next_id`, so don't mix the two on one generator.
+    #[cfg(feature = "secure")]
+    pub fn try_next_id_random(&self, node_id: NodeId) -> Result<u64, GenError> {
+        let node_id = node_id.get();
+        self.sequence_for(node_id)?;
+        let window_size = 1u64 << self.inc_bits;
+        let mut wait_started = None;
+        loop {
+            let now = self.time_since_epoch()?;
+            let mut window = self
+                .random_window
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            if now > window.millis {
+                window.millis = now;
+                window.used.clear();
+                window.reported = false;
+            } else if now < window.millis {
+                let behind = window.millis - now;
+                if self.monotonic_tolerance.is_none_or(|t| behind > t) {
+                    return Err(GenError::ClockWentBackwards);
+                }
+            }
+            if (window.used.len() as u64) < window_size {
+                let value = loop {
+                    let value = random_u64() & field_mask(self.inc_bits);
+                    if window.used.insert(value) {
+                        break value;
+                    }
+                };
+                let millis = window.millis;
+                drop(window);
+                self.report_rollover_wait(wait_started);
+                return Ok(self.generate_id(millis, node_id, value));
+            }
+            drop(window);
+            self.start_rollover_wait(&mut wait_started, || {
+                let mut window = self
+                    .random_window
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+                let spent = window.used.len() as u64 >= window_size;
+                spent && !std::mem::replace(&mut window.reported, true)
+            });
+            self.rollover_wait();
+        }
+    }
+
+    #[cfg(feature = "secure")]
+    pub fn next_id_random(&self, node_id: NodeId) -> u64 {
+        self.try_next_id_random(node_id)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    // Like `try_next_id`, but once the millisecond's sequence is spent it
+    // sleeps on the tokio timer instead of spinning the thread
+    #[cfg(feature = "tokio")]
+    pub async fn try_next_id_async(&self, node_id: NodeId) -> Result<u64, GenError> {
+        let node_id = node_id.get();
+        let sequence = self.sequence_for(node_id)?;
+        let mut wait_started = None;
+        loop {
+            if let Some((time_since_epoch, incrementing_id, _)) =
+                self.try_reserve_sequence(sequence, 1)?
+            {
+                self.report_rollover_wait(wait_started);
+                return Ok(self.generate_id(time_since_epoch, node_id, incrementing_id));
+            }
+            self.start_rollover_wait(&mut wait_started, || {
+                sequence.lock().report_exhaustion(self.inc_bits)
+            });
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    pub async fn next_id_async(&self, node_id: NodeId) -> u64 {
+        self.try_next_id_async(node_id)
+            .await
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    // Ids come back sorted: a block can wrap the 10-bit sequence within one
+    // millisecond, which would otherwise put a smaller id after a larger one.
+    pub fn try_next_ids(&self, node_id: NodeId, count: usize) -> Result<Vec<u64>, GenError> {
+        let node_id = node_id.get();
+        let sequence = self.sequence_for(node_id)?;
+        let mut ids = Vec::with_capacity(count);
+
+        while ids.len() < count {
+            let wanted = (count - ids.len()) as u64;
+            let (time_since_epoch, first, granted) = self.reserve_sequence(sequence, wanted)?;
+            ids.extend(
+                (0..granted)
+                    .map(|i| self.generate_id(time_since_epoch, node_id, first.wrapping_add(i))),
+            );
+        }
+
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    pub fn next_ids(&self, node_id: NodeId, count: usize) -> Vec<u64> {
+        self.try_next_ids(node_id, count)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    // One id per entry of `nodes`, in the same order. Every node id is checked
+    // before any sequence value is spent.
+    pub fn next_ids_for_nodes(&self, nodes: &[NodeId]) -> Result<Vec<u64>, GenError> {
+        for &node_id in nodes {
+            self.sequence_for(node_id.get())?;
+        }
+        nodes
+            .iter()
+            .map(|&node_id| self.try_next_id(node_id))
+            .collect()
+    }
+
+    // Reserves `count` ids from the current millisecond whose sequence values
+    // follow on from each other, so the block is every id from `start_id` to
+    // `end_id` in steps of one sequence value. Fails rather than waiting when
+    // the millisecond doesn't have that many left before the sequence field
+    // wraps, so the caller can retry in the next one. A count of 0 is refused.
+    pub fn reserve_block(&self, node_id: NodeId, count: u16) -> Result<BlockReservation, GenError> {
+        let node_id = node_id.get();
+        let sequence = self.sequence_for(node_id)?;
+        let window_size: u64 = 1 << self.inc_bits;
+        let mask = window_size - 1;
+
+        let mut state = sequence.lock();
+        let now = self.now_for(&state)?;
+        state.advance_window(now, self.window_policy())?;
+        // With nothing issued in this window yet, it can start at sequence 0
+        // and leave all of it unwrapped
+        state.align_window(self.inc_bits);
+
+        let used = state.next_id.wrapping_sub(state.window_start);
+        let unwrapped = window_size - (state.next_id & mask);
+        let remaining = window_size.saturating_sub(used).min(unwrapped);
+        if count == 0 || count as u64 > remaining {
+            return Err(GenError::BlockUnavailable {
+                requested: count,
+                remaining,
+            });
+        }
+
+        let first = state.next_id;
+        state.next_id = first.wrapping_add(count as u64);
+        let time_since_epoch = state.window_millis;
+        drop(state);
+
+        for _ in 0..count {
+            self.metrics.on_id_generated();
+        }
+        Ok(BlockReservation {
+            start_id: self.pack_id(time_since_epoch, node_id, first),
+            end_id: self.pack_id(
+                time_since_epoch,
+                node_id,
+                first.wrapping_add(count as u64 - 1),
+            ),
+            timestamp_millis: self.epoch + time_since_epoch,
+            node_id: NodeId(node_id),
+            count,
+        })
+    }
+
+    pub fn iter(&self, node_id: NodeId) -> IdStream<'_> {
+        IdStream {
+            generator: self,
+            node_id,
+        }
+    }
+
+    // 128-bit ids are laid out as a version 8 UUID, so they can be stored in
+    // UUID columns as they are:
+    //
+    //   time 48 | version 4 | config 4 | shard 8 | variant 2 | node 16 | sequence 16 | random 30
+    //
+    // The sequence field holds the raw counter, so it never repeats within a
+    // millisecond, and the random tail adds entropy on top.
+    pub fn try_next_u128(&self, node_id: NodeId) -> Result<u128, GenError> {
+        let node_id = node_id.get();
+        let sequence = self.sequence_for(node_id)?;
+        // The node field is 16 bits whatever the node bits
+        NodeId::checked(node_id as u64, 1 << 16)?;
+        // The sequence field is at least as wide as inc_bits, so it takes the
+        // counter's low 16 bits rather than just the window's
+        let (time_since_epoch, counter, _) = self.reserve_sequence(sequence, 1)?;
+        self.metrics.on_id_generated();
+
+        Ok(((time_since_epoch as u128 & U128_TIME_MASK) << 80)
+            | (U128_VERSION << 76)
+            | ((self.config_id as u128 & 0xf) << 72)
+            | (U128_VARIANT << 62)
+            | ((node_id as u128) << 46)
+            | (((counter & 0xffff) as u128) << 30)
+            | (rand::random::<u32>() as u128 & U128_RANDOM_MASK))
+    }
+
+    pub fn next_u128(&self, node_id: NodeId) -> u128 {
+        self.try_next_u128(node_id)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn decode_u128(&self, id: u128) -> DecodedId {
+        DecodedId {
+            time: ((id >> 80) & U128_TIME_MASK) as u64,
+            node_id: ((id >> 46) & 0xffff) as u64,
+            shard_id: ((id >> 64) & 0xff) as u16,
+            incrementing_id: ((id >> 30) & 0xffff) as u64,
+            config_id: ((id >> 72) & 0xf) as u8,
+        }
+    }
+
+    #[cfg(feature = "uuid")]
+    pub fn next_uuid(&self, node_id: NodeId) -> uuid::Uuid {
+        uuid::Uuid::from_u128(self.next_u128(node_id))
+    }
+
+    #[cfg(feature = "uuid")]
+    pub fn decode_uuid(&self, id: &uuid::Uuid) -> DecodedId {
+        self.decode_u128(id.as_u128())
+    }
+
+    // The decoded fields plus the absolute `timestamp_millis`, ready to return
+    // from an HTTP handler
+    #[cfg(feature = "json")]
+    pub fn decode_to_json(&self, id: u64) -> serde_json::Value {
+        let decoded = self.decode_id(id);
+        serde_json::json!({
+            "time": decoded.time,
+            "node_id": decoded.node_id,
+            "shard_id": decoded.shard_id,
+            "incrementing_id": decoded.incrementing_id,
+            "config_id": decoded.config_id,
+            "timestamp_millis": decoded.timestamp_millis(self.epoch),
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+/// An endless stream of ids for one node, from `IdGenerator::iter`. Each item
+/// comes from `next_id`, so it panics under the same conditions.
+pub struct IdStream<'a> {
+    generator: &'a IdGenerator,
+    node_id: NodeId,
+}
+
+#[cfg(feature = "std")]
+impl Iterator for IdStream<'_> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        Some(self.generator.next_id(self.node_id))
+    }
+}
+
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockReservation {
+    pub start_id: u64,
+    pub end_id: u64,
+    /// The millisecond every id in the block is stamped with, since the UNIX
+    /// epoch.
+    pub timestamp_millis: u64,
+    pub node_id: NodeId,
+    pub count: u16,
+}
+
+#[cfg(feature = "std")]
+impl BlockReservation {
+    /// Every id in the block, from `start_id` to `end_id`.
+    pub fn ids(&self) -> impl Iterator<Item = u64> {
+        // Neighbouring ids differ only in the sequence field
+        let step = match self.count {
+            1 => 0,
+            n => (self.end_id - self.start_id) / (n as u64 - 1),
+        };
+        let start = self.start_id;
+        (0..self.count as u64).map(move |i| start + i * step)
+    }
+}
+
+#[cfg(feature = "std")]
+/// An id that orders by its decoded fields, time first, then node, shard and
+/// sequence, with the config id breaking any remaining tie. Raw `u64` order
+/// agrees within one layout but not across layouts.
+#[derive(Clone, Copy)]
+pub struct SortableId<'a>(pub u64, pub &'a IdGenerator);
+
+#[cfg(feature = "std")]
+impl SortableId<'_> {
+    fn key(&self) -> (u64, u64, u16, u64, u8) {
+        let decoded = self.1.decode_id(self.0);
+        (
+            decoded.time,
+            decoded.node_id,
+            decoded.shard_id,
+            decoded.incrementing_id,
+            decoded.config_id,
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl fmt::Debug for SortableId<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SortableId").field(&self.0).finish()
+    }
+}
+
+#[cfg(feature = "std")]
+impl PartialEq for SortableId<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Eq for SortableId<'_> {}
+
+#[cfg(feature = "std")]
+impl PartialOrd for SortableId<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(feature = "std")]
+impl Ord for SortableId<'_> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+// A generator for a single thread. Its counter is a plain `Cell`, so issuing
+// an id takes no lock, and the `Cell` makes it `!Sync`: the compiler won't
+// let it be shared between threads, which is what makes the lock unneeded.
+// Ids are the same as from an `IdGenerator` with the same layout and clock.
+#[cfg(feature = "std")]
+pub struct LocalIdGenerator {
+    config: IdConfig,
+    max_nodes: u32,
+    sequence: Cell<SequenceState>,
+    clock: Box<dyn Clock>,
+}
+
+#[cfg(feature = "std")]
+impl LocalIdGenerator {
+    pub fn new(preset: ConfigPreset, epoch: u64) -> Self {
+        Self::with_clock(preset, epoch, SystemClock)
+    }
+
+    pub fn with_clock<C: Clock + 'static>(preset: ConfigPreset, epoch: u64, clock: C) -> Self {
+        let config = IdConfig::new(preset, epoch);
+        Self {
+            config,
+            max_nodes: node_count(config.node_id_bits()),
+            sequence: Cell::new(SequenceState::starting_at(0)),
+            clock: Box::new(clock),
+        }
+    }
+
+    pub fn config(&self) -> IdConfig {
+        self.config
+    }
+
+    pub fn node_id(&self, raw: u64) -> Result<NodeId, GenError> {
+        NodeId::checked(raw, self.max_nodes)
+    }
+
+    pub fn try_next_id(&self, node_id: NodeId) -> Result<u64, GenError> {
+        let node_id = NodeId::checked(node_id.get() as u64, self.max_nodes)?.get();
+
+        loop {
+            let millis = self.clock.now_millis();
+            let now = millis
+                .checked_sub(self.config.epoch)
+                .ok_or(GenError::ClockBehindEpoch {
+                    epoch: self.config.epoch,
+                    now: millis,
+                })?;
+            let mut state = self.sequence.get();
+            let reserved = state.reserve(now, WindowPolicy::strict(self.config.inc_bits), 1);
+            self.sequence.set(state);
+            match reserved? {
+                Some((time_since_epoch, counter, _)) => {
+                    // As in `IdGenerator`, `encode` masks the counter down
+                    // to the sequence field
+                    return Ok(self
+                        .config
+                        .encode(time_since_epoch, node_id, counter as u16));
+                }
+                None => hint::spin_loop(),
+            }
+        }
+    }
+
+    pub fn next_id(&self, node_id: NodeId) -> u64 {
+        self.try_next_id(node_id)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn decode_id(&self, id: u64) -> DecodedId {
+        self.config.decode(id)
+    }
+}
+
+// A generator whose millisecond and count of ids issued in it are packed
+// into one `AtomicU64` and moved on together by compare-and-swap, with no
+// lock. Each millisecond's sequence starts at 0, as with
+// `reset_sequence_each_ms`.
+//
+// Uniqueness only needs every (millisecond, sequence) pair to be handed out
+// once. A swap succeeds only against the exact word it read, so two threads
+// can never both take the same pair, whatever order other threads observe
+// the swaps in. No other memory is published through the word, so
+// `Ordering::Relaxed`, the default, is enough; `with_ordering` can ask for
+// `SeqCst` or the others to compare, see `cargo bench`.
+#[cfg(feature = "std")]
+pub struct AtomicIdGenerator {
+    config: IdConfig,
+    max_nodes: u32,
+    // The window's offset from the epoch above the `inc_bits + 1` bits
+    // counting ids issued in it
+    state: AtomicU64,
+    ordering: Ordering,
+    clock: Box<dyn Clock>,
+}
+
+#[cfg(feature = "std")]
+impl AtomicIdGenerator {
+    pub fn new(preset: ConfigPreset, epoch: u64) -> Self {
+        Self::with_clock(preset, epoch, SystemClock)
+    }
+
+    pub fn with_clock<C: Clock + 'static>(preset: ConfigPreset, epoch: u64, clock: C) -> Self {
+        let config = IdConfig::new(preset, epoch);
+        Self {
+            config,
+            max_nodes: node_count(config.node_id_bits()),
+            state: AtomicU64::new(0),
+            ordering: Ordering::Relaxed,
+            clock: Box::new(clock),
+        }
+    }
+
+    // The ordering for the swap that takes a sequence value
+    pub fn with_ordering(mut self, ordering: Ordering) -> Self {
+        self.ordering = ordering;
+        self
+    }
+
+    pub fn config(&self) -> IdConfig {
+        self.config
+    }
+
+    pub fn node_id(&self, raw: u64) -> Result<NodeId, GenError> {
+        NodeId::checked(raw, self.max_nodes)
+    }
+
+    pub fn try_next_id(&self, node_id: NodeId) -> Result<u64, GenError> {
+        let node_id = NodeId::checked(node_id.get() as u64, self.max_nodes)?.get();
+
+        // A load can't take the release half of the ordering, nor can a
+        // failed swap, which is only a load
+        let load = match self.ordering {
+            Ordering::Relaxed | Ordering::Release => Ordering::Relaxed,
+            Ordering::SeqCst => Ordering::SeqCst,
+            _ => Ordering::Acquire,
+        };
+        let count_bits = self.config.inc_bits as u32 + 1;
+        let count_mask = (1u64 << count_bits) - 1;
+        let window_size = 1u64 << self.config.inc_bits;
+        let mut current = self.state.load(load);
+        loop {
+            let millis = self.clock.now_millis();
+            let now = millis
+                .checked_sub(self.config.epoch)
+                .ok_or(GenError::ClockBehindEpoch {
+                    epoch: self.config.epoch,
+                    now: millis,
+                })?;
+            if now > field_mask(self.config.epoch_bits) {
+                return Err(GenError::TimestampOutOfRange {
+                    timestamp_millis: millis,
+                    max: self
+                        .config
+                        .epoch
+                        .saturating_add(field_mask(self.config.epoch_bits)),
+                });
+            }
+
+            let (window, used) = (current >> count_bits, current & count_mask);
+            let (window, used) = match now.cmp(&window) {
+                core::cmp::Ordering::Greater => (now, 0),
+                core::cmp::Ordering::Equal => (window, used),
+                core::cmp::Ordering::Less => return Err(GenError::ClockWentBackwards),
+            };
+            if used >= window_size {
+                // Every value in this millisecond is spent
+                hint::spin_loop();
+                current = self.state.load(load);
+                continue;
+            }
+
+            let next = window << count_bits | (used + 1);
+            match self
+                .state
+                .compare_exchange_weak(current, next, self.ordering, load)
+            {
+                Ok(_) => return Ok(self.config.encode(window, node_id, used as u16)),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    pub fn next_id(&self, node_id: NodeId) -> u64 {
+        self.try_next_id(node_id)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn decode_id(&self, id: u64) -> DecodedId {
+        self.config.decode(id)
+    }
+}
+
+/// Wraps a generator and remembers every id it has seen, so integration tests
+/// can catch rollover and clock bugs that would otherwise only show up as rare
+/// key collisions in production. Memory grows with every id, so this is for
+/// tests only.
+#[cfg(feature = "testing")]
+pub struct DedupGuard {
+    generator: IdGenerator,
+    seen: Mutex<std::collections::HashSet<u64>>,
+}
+
+#[cfg(feature = "testing")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DuplicateId(pub u64);
+
+#[cfg(feature = "testing")]
+impl fmt::Display for DuplicateId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Id {} was issued more than once", self.0)
+    }
+}
+
+#[cfg(feature = "testing")]
+impl core::error::Error for DuplicateId {}
+
+#[cfg(feature = "testing")]
+impl DedupGuard {
+    pub fn new(generator: IdGenerator) -> Self {
+        Self {
+            generator,
+            seen: Mutex::new(std::collections::HashSet::new()),
+        }
+    }
+
+    pub fn generator(&self) -> &IdGenerator {
+        &self.generator
+    }
+
+    // Records an id, including ones issued by other generators that are
+    // supposed to share its id space
+    pub fn check(&self, id: u64) -> Result<u64, DuplicateId> {
+        let mut seen = self.seen.lock().unwrap_or_else(PoisonError::into_inner);
+        if seen.insert(id) {
+            Ok(id)
+        } else {
+            Err(DuplicateId(id))
+        }
+    }
+
+    pub fn next_id(&self, node_id: NodeId) -> u64 {
+        self.check(self.generator.next_id(node_id))
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A generator whose layout is fixed at compile time, with the default config
+/// and sequence widths. The shifts and masks are constants, so encoding and
+/// decoding compile down to fixed shifts instead of reading the layout on every
+/// call. Ids match an `IdGenerator` built from
+/// `Custom(epoch, EPOCH_BITS, NODE_BITS, SHARD_BITS, INC_BITS, config_id)`.
+#[cfg(feature = "std")]
+pub struct ConstIdGenerator<const EPOCH_BITS: u8, const NODE_BITS: u8, const SHARD_BITS: u8> {
+    epoch: u64,
+    config_id: u8,
+    sequence: Sequence,
+    clock: Box<dyn Clock>,
+}
+
+#[cfg(feature = "std")]
+impl<const EPOCH_BITS: u8, const NODE_BITS: u8, const SHARD_BITS: u8>
+    ConstIdGenerator<EPOCH_BITS, NODE_BITS, SHARD_BITS>
+{
+    const INC_SHIFT: u8 = CONFIG_BITS;
+    const SHARD_SHIFT: u8 = Self::INC_SHIFT + INC_BITS;
+    const NODE_SHIFT: u8 = Self::SHARD_SHIFT + SHARD_BITS;
+    const TIME_SHIFT: u8 = Self::NODE_SHIFT + NODE_BITS;
+
+    pub const MAX_NODES: u32 = node_count(NODE_BITS);
+
+    pub fn new(epoch: u64, config_id: u8) -> Result<Self, ConfigError> {
+        Self::with_clock(epoch, config_id, SystemClock)
+    }
+
+    pub fn with_clock<C: Clock + 'static>(
+        epoch: u64,
+        config_id: u8,
+        clock: C,
+    ) -> Result<Self, ConfigError> {
+        // The same checks as `IdConfig::validate`, minus the config id, which
+        // is only known at run time
+        const {
+            assert!(EPOCH_BITS > 0, "epoch_bits must be non-zero");
+            assert!(NODE_BITS > 0, "node_bits must be non-zero");
+            assert!(
+                CONFIG_BITS as u32
+                    + INC_BITS as u32
+                    + SHARD_BITS as u32
+                    + NODE_BITS as u32
+                    + EPOCH_BITS as u32
+                    <= 64,
+                "layout needs more than 64 bits"
+            );
+        }
+
+        if config_id as u64 > field_mask(CONFIG_BITS) {
+            return Err(ConfigError::ConfigIdTooLarge {
+                config_id,
+                config_bits: CONFIG_BITS,
+            });
+        }
+        Ok(Self {
+            epoch,
+            config_id,
+            sequence: Sequence::new(),
+            clock: Box::new(clock),
+        })
+    }
+
+    // The equivalent runtime layout, for decoding elsewhere or building a
+    // matching `IdGenerator`
+    pub fn config(&self) -> IdConfig {
+        IdConfig {
+            epoch: self.epoch,
+            epoch_bits: EPOCH_BITS,
+            node_bits: NODE_BITS,
+            shard_bits: SHARD_BITS,
+            config_id: self.config_id,
+            config_bits: CONFIG_BITS,
+            inc_bits: INC_BITS,
+            shard_checksum_bits: 0,
+            shard2_bits: 0,
+            salt_bits: 0,
+        }
+    }
+
+    pub fn node_id(&self, raw: u64) -> Result<NodeId, GenError> {
+        NodeId::checked(raw, Self::MAX_NODES)
+    }
+
+    pub fn try_next_id(&self, node_id: NodeId) -> Result<u64, GenError> {
+        let node_id = NodeId::checked(node_id.get() as u64, Self::MAX_NODES)?.get();
+
+        let time_since_epoch = || {
+            let millis = self.clock.now_millis();
+            millis
+                .checked_sub(self.epoch)
+                .ok_or(GenError::ClockBehindEpoch {
+                    epoch: self.epoch,
+                    now: millis,
+                })
+        };
+        let (time_since_epoch, incrementing_id) = loop {
+            match self.sequence.try_reserve(
+                |_| time_since_epoch(),
+                WindowPolicy::strict(INC_BITS),
+                1,
+            )? {
+                Some((time, first, _)) => break (time, first),
+                None => hint::spin_loop(),
+            }
+        };
+        Ok(self.encode(time_since_epoch, node_id, incrementing_id))
+    }
+
+    pub fn next_id(&self, node_id: NodeId) -> u64 {
+        self.try_next_id(node_id)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn encode(&self, time_since_epoch: u64, node_id: u32, incrementing_id: u64) -> u64 {
+        (time_since_epoch & field_mask(EPOCH_BITS)) << Self::TIME_SHIFT
+            | (node_id as u64 & field_mask(NODE_BITS)) << Self::NODE_SHIFT
+            | (incrementing_id & field_mask(INC_BITS)) << Self::INC_SHIFT
+            | self.config_id as u64
+    }
+
+    pub fn decode_id(&self, id: u64) -> DecodedId {
+        DecodedId {
+            time: id >> Self::TIME_SHIFT & field_mask(EPOCH_BITS),
+            node_id: id >> Self::NODE_SHIFT & field_mask(NODE_BITS),
+            shard_id: (id >> Self::SHARD_SHIFT & field_mask(SHARD_BITS)) as u16,
+            incrementing_id: id >> Self::INC_SHIFT & field_mask(INC_BITS),
+            config_id: (id & field_mask(CONFIG_BITS)) as u8,
+        }
+    }
+}
+
+/// Where `NodeIdAllocator` keeps its leases: one per node id, held by an
+/// owner until it expires. Implementations backed by a shared database or
+/// coordination service let processes on different hosts pick distinct node
+/// ids. Times are unix millis from the allocator's clock.
+#[cfg(feature = "cluster")]
+pub trait NodeStore: Send + Sync {
+    /// Takes the lease on `node_id` for `owner` until `expires_at` if nobody
+    /// holds it or the last lease ran out before `now`. Returns whether it did.
+    fn try_claim(&self, node_id: u32, owner: u64, now: u64, expires_at: u64) -> bool;
+    /// Extends a lease `owner` still holds. Returns false if it expired before
+    /// `now` or someone else holds it.
+    fn renew(&self, node_id: u32, owner: u64, now: u64, expires_at: u64) -> bool;
+    /// Gives up a lease early; does nothing if `owner` doesn't hold it.
+    fn release(&self, node_id: u32, owner: u64);
+}
+
+// Leases in a map, for tests and for processes sharing one store in memory
+#[cfg(feature = "cluster")]
+#[derive(Debug, Default)]
+pub struct MemoryNodeStore {
+    // node id -> (owner, expires_at)
+    leases: Mutex<std::collections::HashMap<u32, (u64, u64)>>,
+}
+
+#[cfg(feature = "cluster")]
+impl MemoryNodeStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn leases(&self) -> MutexGuard<'_, std::collections::HashMap<u32, (u64, u64)>> {
+        self.leases.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(feature = "cluster")]
+impl NodeStore for MemoryNodeStore {
+    fn try_claim(&self, node_id: u32, owner: u64, now: u64, expires_at: u64) -> bool {
+        let mut leases = self.leases();
+        match leases.get(&node_id) {
+            Some(&(_, held_until)) if held_until >= now => false,
+            _ => {
+                leases.insert(node_id, (owner, expires_at));
+                true
+            }
+        }
+    }
+
+    fn renew(&self, node_id: u32, owner: u64, now: u64, expires_at: u64) -> bool {
+        match self.leases().get_mut(&node_id) {
+            Some(lease) if lease.0 == owner && lease.1 >= now => {
+                lease.1 = expires_at;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn release(&self, node_id: u32, owner: u64) {
+        let mut leases = self.leases();
+        if leases
+            .get(&node_id)
+            .is_some_and(|&(holder, _)| holder == owner)
+        {
+            leases.remove(&node_id);
+        }
+    }
+}
+
+#[cfg(feature = "cluster")]
+impl<S: NodeStore + ?Sized> NodeStore for Arc<S> {
+    fn try_claim(&self, node_id: u32, owner: u64, now: u64, expires_at: u64) -> bool {
+        (**self).try_claim(node_id, owner, now, expires_at)
+    }
+
+    fn renew(&self, node_id: u32, owner: u64, now: u64, expires_at: u64) -> bool {
+        (**self).renew(node_id, owner, now, expires_at)
+    }
+
+    fn release(&self, node_id: u32, owner: u64) {
+        (**self).release(node_id, owner)
+    }
+}
+
+#[cfg(feature = "cluster")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocError {
+    /// Every node id below `max_nodes` is leased to someone.
+    PoolExhausted { max_nodes: u32 },
+    /// The lease expired, or another owner took the node id, before renewal.
+    LeaseLost { node_id: u32 },
+}
+
+#[cfg(feature = "cluster")]
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AllocError::PoolExhausted { max_nodes } => {
+                write!(f, "All {} node ids are leased", max_nodes)
+            }
+            AllocError::LeaseLost { node_id } => {
+                write!(f, "The lease on node id {} was lost", node_id)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "cluster")]
+impl core::error::Error for AllocError {}
+
+/// A node id held under a lease from a `NodeIdAllocator`.
+#[cfg(feature = "cluster")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeLease {
+    pub node_id: u32,
+    /// Unix millis the lease runs until unless renewed.
+    pub expires_at: u64,
+    owner: u64,
+}
+
+// Hands out node ids no other allocator on the same store holds. Nothing
+// renews leases in the background: call `renew` well within the lease
+// duration, and stop issuing ids for a node id whose lease was lost, since
+// another process may already be using it.
+#[cfg(feature = "cluster")]
+pub struct NodeIdAllocator {
+    store: Arc<dyn NodeStore>,
+    lease_ms: u64,
+    owner: u64,
+    clock: Arc<dyn Clock>,
+}
+
+#[cfg(feature = "cluster")]
+impl NodeIdAllocator {
+    pub fn new<S: NodeStore + 'static>(store: S, lease_ms: u64) -> Self {
+        Self::with_clock(store, lease_ms, SystemClock)
+    }
+
+    pub fn with_clock<S: NodeStore + 'static, C: Clock + 'static>(
+        store: S,
+        lease_ms: u64,
+        clock: C,
+    ) -> Self {
+        Self {
+            store: Arc::new(store),
+            lease_ms,
+            // Tells this allocator's leases apart from everyone else's
+            owner: rand::random(),
+            clock: Arc::new(clock),
+        }
+    }
+
+    // Leases the lowest free node id below `max_nodes`
+    pub fn allocate(&self, max_nodes: u32) -> Result<NodeLease, AllocError> {
+        let now = self.clock.now_millis();
+        let expires_at = now.saturating_add(self.lease_ms);
+        (0..max_nodes)
+            .find(|&node_id| self.store.try_claim(node_id, self.owner, now, expires_at))
+            .map(|node_id| NodeLease {
+                node_id,
+                expires_at,
+                owner: self.owner,
+            })
+            .ok_or(AllocError::PoolExhausted { max_nodes })
+    }
+
+    pub fn renew(&self, lease: &NodeLease) -> Result<NodeLease, AllocError> {
+        let now = self.clock.now_millis();
+        let expires_at = now.saturating_add(self.lease_ms);
+        if self
+            .store
+            .renew(lease.node_id, lease.owner, now, expires_at)
+        {
+            Ok(NodeLease {
+                expires_at,
+                ..*lease
+            })
+        } else {
+            Err(AllocError::LeaseLost {
+                node_id: lease.node_id,
+            })
+        }
+    }
+
+    pub fn release(&self, lease: NodeLease) {
+        self.store.release(lease.node_id, lease.owner);
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<&IdGenerator> for IdConfig {
+    fn from(gen: &IdGenerator) -> Self {
+        Self {
+            epoch: gen.epoch,
+            epoch_bits: gen.epoch_bits,
+            node_bits: gen.node_bits,
+            shard_bits: gen.shard_bits,
+            config_id: gen.config_id,
+            config_bits: gen.config_bits,
+            inc_bits: gen.inc_bits,
+            shard_checksum_bits: gen.shard_checksum_bits,
+            shard2_bits: gen.shard2_bits,
+            salt_bits: gen.salt_bits,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+#[derive(Clone)]
+pub struct IdGeneratorBuilder {
+    epoch: u64,
+    epoch_bits: u8,
+    node_bits: u8,
+    shard_bits: u8,
+    inc_bits: u8,
+    config_bits: u8,
+    config_id: u8,
+    shard_checksum_bits: u8,
+    shard1_bits: Option<u8>,
+    shard2_bits: u8,
+    salt_bits: u8,
+    clock: Option<Arc<dyn Clock>>,
+    metrics: Option<Arc<dyn Metrics>>,
+    monotonic_tolerance: Option<u64>,
+    exhaustion_threshold: f64,
+    rollover_strategy: RolloverStrategy,
+    reset_sequence_each_ms: bool,
+    on_exhaustion: Option<Arc<dyn Fn() + Send + Sync>>,
+}
+
+#[cfg(feature = "std")]
+impl fmt::Debug for IdGeneratorBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IdGeneratorBuilder")
+            .field("epoch", &self.epoch)
+            .field("epoch_bits", &self.epoch_bits)
+            .field("node_bits", &self.node_bits)
+            .field("shard_bits", &self.shard_bits)
+            .field("inc_bits", &self.inc_bits)
+            .field("config_bits", &self.config_bits)
+            .field("config_id", &self.config_id)
+            .field("shard_checksum_bits", &self.shard_checksum_bits)
+            .field("shard1_bits", &self.shard1_bits)
+            .field("shard2_bits", &self.shard2_bits)
+            .field("salt_bits", &self.salt_bits)
+            .field("custom_clock", &self.clock.is_some())
+            .field("custom_metrics", &self.metrics.is_some())
+            .field("monotonic_tolerance", &self.monotonic_tolerance)
+            .field("exhaustion_threshold", &self.exhaustion_threshold)
+            .field("rollover_strategy", &self.rollover_strategy)
+            .field("reset_sequence_each_ms", &self.reset_sequence_each_ms)
+            .field("on_exhaustion", &self.on_exhaustion.is_some())
+            .finish()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for IdGeneratorBuilder {
+    // Starts from the ShortEpochMaxNodes layout
+    fn default() -> Self {
+        Self {
+            epoch: DEFAULT_EPOCH,
+            epoch_bits: 37,
+            node_bits: 14,
+            shard_bits: 0,
+            inc_bits: INC_BITS,
+            config_bits: CONFIG_BITS,
+            config_id: 3,
+            shard_checksum_bits: 0,
+            shard1_bits: None,
+            shard2_bits: 0,
+            salt_bits: 0,
+            clock: None,
+            metrics: None,
+            monotonic_tolerance: None,
+            exhaustion_threshold: DEFAULT_EXHAUSTION_THRESHOLD,
+            rollover_strategy: RolloverStrategy::Spin,
+            reset_sequence_each_ms: false,
+            on_exhaustion: None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl IdGeneratorBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn epoch(mut self, epoch: u64) -> Self {
+        self.epoch = epoch;
+        self
+    }
+
+    // Epochs are unsigned milliseconds, so `dt` can't be before 1970
+    #[cfg(feature = "chrono")]
+    pub fn epoch_datetime(self, dt: chrono::DateTime<chrono::Utc>) -> Self {
+        let epoch = u64::try_from(dt.timestamp_millis())
+            .unwrap_or_else(|_| panic!("Epoch {} is before the UNIX epoch", dt));
+        self.epoch(epoch)
+    }
+
+    pub fn epoch_bits(mut self, epoch_bits: u8) -> Self {
+        self.epoch_bits = epoch_bits;
+        self
+    }
+
+    pub fn node_bits(mut self, node_bits: u8) -> Self {
+        self.node_bits = node_bits;
+        self
+    }
+
+    pub fn shard_bits(mut self, shard_bits: u8) -> Self {
+        self.shard_bits = shard_bits;
+        self
+    }
+
+    pub fn inc_bits(mut self, inc_bits: u8) -> Self {
+        self.inc_bits = inc_bits;
+        self
+    }
+
+    pub fn config_id(mut self, config_id: u8) -> Self {
+        self.config_id = config_id;
+        self
+    }
+
+    // Without the config field the sequence starts at bit 0, as in a classic
+    // snowflake. The config id becomes 0, the only value that fits; ids then
+    // can't be told apart from other config-less layouts by `config_id_of`.
+    pub fn with_config_field(mut self, enabled: bool) -> Self {
+        self.config_bits = if enabled { CONFIG_BITS } else { 0 };
+        if !enabled {
+            self.config_id = 0;
+        }
+        self
+    }
+
+    // Stores a checksum of the shard in this many bits above the time field,
+    // so `try_decode_id` and `verify_shard_checksum` catch shard bits that
+    // changed without going through `derive_sharded_id`
+    pub fn shard_checksum_bits(mut self, bits: u8) -> Self {
+        self.shard_checksum_bits = bits;
+        self
+    }
+
+    // Splits the shard into two levels, e.g. region and partition, set
+    // together with `derive_two_level_shard`. The shard field becomes
+    // `shard1_bits + shard2_bits` wide, replacing `shard_bits`.
+    pub fn shard1_bits(mut self, bits: u8) -> Self {
+        self.shard1_bits = Some(bits);
+        self
+    }
+
+    pub fn shard2_bits(mut self, bits: u8) -> Self {
+        self.shard2_bits = bits;
+        self
+    }
+
+    // Gives the top `bits` of the node field to a random salt drawn when the
+    // generator is built, leaving the rest for node ids. Two processes that
+    // end up with the same node id then only collide if their salts match.
+    #[cfg(feature = "salt")]
+    pub fn salt_bits(mut self, bits: u8) -> Self {
+        self.salt_bits = bits;
+        self
+    }
+
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    pub fn metrics<M: Metrics + 'static>(mut self, metrics: M) -> Self {
+        self.metrics = Some(Arc::new(metrics));
+        self
+    }
+
+    /// Keeps ids increasing when the clock steps back by up to `tolerance_ms`:
+    /// the generator keeps using the last millisecond it issued ids in and
+    /// bumps the sequence, waiting for the clock to catch up if that
+    /// millisecond runs out. Larger regressions still fail with
+    /// `GenError::ClockWentBackwards`.
+    pub fn monotonic(mut self, tolerance_ms: u64) -> Self {
+        self.monotonic_tolerance = Some(tolerance_ms);
+        self
+    }
+
+    // The fraction of the time field `try_next_id_guarded` lets the clock use,
+    // 0.9 by default
+    pub fn exhaustion_threshold(mut self, ratio: f64) -> Self {
+        self.exhaustion_threshold = ratio;
+        self
+    }
+
+    pub fn rollover_strategy(mut self, strategy: RolloverStrategy) -> Self {
+        self.rollover_strategy = strategy;
+        self
+    }
+
+    // Starts the sequence field at 0 in every new millisecond, as classic
+    // snowflakes do. Off by default, where the sequence carries on from the
+    // previous millisecond's; both keep ids unique.
+    pub fn reset_sequence_each_ms(mut self, reset: bool) -> Self {
+        self.reset_sequence_each_ms = reset;
+        self
+    }
+
+    // Calls `callback` whenever a millisecond's sequence runs out and an id
+    // has to wait for the next one, once per spent millisecond however many
+    // threads end up waiting on it, before the first wait starts. It runs
+    // on the thread asking for the id, so it should return quickly; it can
+    // shed load elsewhere, but the id is still issued once the clock moves on.
+    pub fn on_exhaustion<F: Fn() + Send + Sync + 'static>(mut self, callback: F) -> Self {
+        self.on_exhaustion = Some(Arc::new(callback));
+        self
+    }
+
+    pub fn build(self) -> Result<IdGenerator, ConfigError> {
+        let mut gen = IdGenerator::from_config(IdConfig {
+            epoch: self.epoch,
+            epoch_bits: self.epoch_bits,
+            node_bits: self.node_bits,
+            shard_bits: self.shard1_bits.map_or(self.shard_bits, |bits| {
+                bits.saturating_add(self.shard2_bits)
+            }),
+            config_id: self.config_id,
+            config_bits: self.config_bits,
+            inc_bits: self.inc_bits,
+            shard_checksum_bits: self.shard_checksum_bits,
+            shard2_bits: self.shard2_bits,
+            salt_bits: self.salt_bits,
+        })?;
+        if let Some(clock) = self.clock {
+            gen.clock = clock;
+        }
+        if let Some(metrics) = self.metrics {
+            gen.metrics = metrics;
+        }
+        gen.monotonic_tolerance = self.monotonic_tolerance;
+        gen.exhaustion_threshold = self.exhaustion_threshold;
+        gen.rollover_strategy = self.rollover_strategy;
+        gen.reset_sequence_each_ms = self.reset_sequence_each_ms;
+        gen.on_exhaustion = self.on_exhaustion;
+        Ok(gen)
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+pub struct WasmIdGenerator {
+    generator: IdGenerator,
+}
+
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+impl WasmIdGenerator {
+    #[wasm_bindgen(constructor)]
+    pub fn new(preset_type: u8, epoch: u64) -> Result<WasmIdGenerator, JsValue> {
+        let preset = match preset_type {
+            0 => ConfigPreset::ShortEpochMaxNodes,
+            1 => ConfigPreset::ShardedConfig,
+            2 => ConfigPreset::Snowflake,
+            3 => ConfigPreset::ShardedConfigWide,
+            _ => return Err(JsValue::from_str("Invalid preset type")),
+        };
+
+        Ok(WasmIdGenerator {
+            generator: IdGenerator::new(preset, epoch),
+        })
+    }
+
+    #[wasm_bindgen]
+    pub fn next_id(&self, node_id: u32) -> Result<u64, JsValue> {
+        self.generator
+            .node_id(node_id as u64)
+            .and_then(|node_id| self.generator.try_next_id(node_id))
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    #[wasm_bindgen]
+    pub fn decode_id(&self, id: u64) -> Result<JsValue, JsValue> {
+        let decoded = self.generator.decode_id(id);
+        serde_wasm_bindgen::to_value(&decoded).map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    #[wasm_bindgen]
+    pub fn derive_sharded_id(&self, original_id: u64, shard: u16) -> Result<u64, JsValue> {
+        self.generator
+            .try_derive_sharded_id(original_id, shard)
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::thread;
+
+    struct MockClock {
+        millis: AtomicU64,
+        reads: AtomicU64,
+    }
+
+    impl MockClock {
+        fn new(millis: u64) -> Arc<Self> {
+            Arc::new(Self {
+                millis: AtomicU64::new(millis),
+                reads: AtomicU64::new(0),
+            })
+        }
+
+        fn set(&self, millis: u64) {
+            self.millis.store(millis, Ordering::SeqCst);
+        }
+
+        fn advance(&self, millis: u64) {
+            self.millis.fetch_add(millis, Ordering::SeqCst);
+        }
+
+        fn reads(&self) -> u64 {
+            self.reads.load(Ordering::SeqCst)
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now_millis(&self) -> u64 {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            self.millis.load(Ordering::SeqCst)
+        }
+    }
+
+    // A node id made for a 20-bit node field, for checking that narrower
+    // layouts refuse it
+    fn wide_node(raw: u64) -> NodeId {
+        IdGenerator::new(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 27, 20, 0, 10, 2),
+            DEFAULT_EPOCH,
+        )
+        .node_id(raw)
+        .unwrap()
+    }
+
+    #[test]
+    fn test_initialization() {
+        let gen_short = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
+        assert_eq!(gen_short.epoch, DEFAULT_EPOCH);
+        assert_eq!(gen_short.epoch_bits, 37);
+        assert_eq!(gen_short.node_bits, 14);
+        assert_eq!(gen_short.max_nodes, 16384);
+        assert_eq!(gen_short.config_id, 3);
+        assert_eq!(gen_short.shard_bits, 0);
+
+        let custom_epoch = 1609459200000;
+        let custom_epoch_bits = 36;
+        let custom_node_bits = 13;
+        let custom_shard_bits = 2;
+        let custom_config_id = 1;
+        let gen_custom = IdGenerator::new(
+            ConfigPreset::Custom(
+                custom_epoch,
+                custom_epoch_bits,
+                custom_node_bits,
+                custom_shard_bits, // Add this parameter
+                10,
+                custom_config_id,
+            ),
+            custom_epoch,
+        );
+        assert_eq!(gen_custom.epoch, custom_epoch);
+        assert_eq!(gen_custom.epoch_bits, custom_epoch_bits);
+        assert_eq!(gen_custom.node_bits, custom_node_bits);
+        assert_eq!(gen_custom.shard_bits, custom_shard_bits);
+        assert_eq!(gen_custom.max_nodes, 8192);
+        assert_eq!(gen_custom.config_id, custom_config_id);
+    }
+
+    #[test]
+    fn test_try_new_validates_bit_budget() {
+        assert!(IdGenerator::try_new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH).is_ok());
+        assert!(IdGenerator::try_new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH).is_ok());
+
+        let result = IdGenerator::try_new(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 40, 14, 5, 10, 2),
+            DEFAULT_EPOCH,
+        );
+        match result {
+            Err(err @ ConfigError::BitBudgetExceeded { overflow, .. }) => {
+                assert_eq!(overflow, 8);
+                let message = err.to_string();
+                assert!(message.contains("40 epoch_bits"));
+                assert!(message.contains("14 node_bits"));
+                assert!(message.contains("5 shard_bits"));
+            }
+            _ => panic!("expected BitBudgetExceeded"),
+        }
+    }
+
+    #[test]
+    fn test_max_nodes_with_wide_node_bits() {
+        let gen = IdGenerator::new(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 31, 16, 0, 10, 2),
+            DEFAULT_EPOCH,
+        );
+        assert_eq!(gen.max_nodes, 65536);
+        let decoded = gen.decode_id(gen.next_id(gen.node_id(65535).unwrap()));
+        assert_eq!(decoded.node_id, 65535);
+
+        let gen = IdGenerator::new(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 32, 15, 0, 10, 2),
+            DEFAULT_EPOCH,
+        );
+        assert_eq!(gen.max_nodes, 32768);
+    }
+
+    #[test]
+    fn test_node_id_out_of_range() {
+        let gen = IdGenerator::new(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 41, 4, 0, 10, 2),
+            DEFAULT_EPOCH,
+        );
+        assert!(gen.try_next_id(gen.node_id(15).unwrap()).is_ok());
+
+        // A node id from a wider layout is checked again
+        assert_eq!(
+            gen.try_next_id(wide_node(16)),
+            Err(GenError::NodeIdOutOfRange {
+                node_id: 16,
+                max_nodes: 16
+            })
+        );
+    }
+
+    #[test]
+    fn test_node_id_construction() {
+        let gen = IdGenerator::new(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 41, 4, 0, 10, 2),
+            DEFAULT_EPOCH,
+        );
+
+        let node = gen.node_id(15).unwrap();
+        assert_eq!(node.get(), 15);
+        assert_eq!(gen.decode_id(gen.next_id(node)).node_id, 15);
+        assert_eq!(gen.node_id(0).map(NodeId::get), Ok(0));
+
+        for raw in [16, 1000, u16::MAX as u64 + 1, u64::MAX] {
+            assert_eq!(
+                gen.node_id(raw),
+                Err(GenError::NodeIdOutOfRange {
+                    node_id: raw,
+                    max_nodes: 16
+                })
+            );
+        }
+
+        // Node fields wider than 16 bits take every node id they can hold
+        let wide = IdGenerator::new(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 27, 20, 0, 10, 2),
+            DEFAULT_EPOCH,
+        );
+        let node = wide.node_id((1 << 20) - 1).unwrap();
+        assert_eq!(wide.decode_id(wide.next_id(node)).node_id, (1 << 20) - 1);
+        // 128-bit ids keep a 16-bit node field
+        assert!(matches!(
+            wide.try_next_u128(node),
+            Err(GenError::NodeIdOutOfRange {
+                max_nodes: 65536,
+                ..
+            })
+        ));
+        assert_eq!(
+            wide.node_id(1 << 20),
+            Err(GenError::NodeIdOutOfRange {
+                node_id: 1 << 20,
+                max_nodes: 1 << 20
+            })
+        );
+    }
+
+    #[test]
+    fn test_per_node_sequences_are_independent() {
+        let gen =
+            IdGenerator::with_per_node_sequences(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
+        let mut node_1 = Vec::new();
+        let mut node_2 = Vec::new();
+        for _ in 0..10 {
+            node_1.push(gen.decode_id(gen.next_id(NodeId(1))).incrementing_id);
+            node_2.push(gen.decode_id(gen.next_id(NodeId(2))).incrementing_id);
+        }
+
+        let expected: Vec<u64> = (0..10).collect();
+        assert_eq!(node_1, expected);
+        assert_eq!(node_2, expected);
+
+        // A shared generator hands out one sequence across both nodes
+        let shared = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
+        assert_eq!(
+            shared.decode_id(shared.next_id(NodeId(1))).incrementing_id,
+            0
+        );
+        assert_eq!(
+            shared.decode_id(shared.next_id(NodeId(2))).incrementing_id,
+            1
+        );
+    }
+
+    #[test]
+    fn test_builder_matches_custom_preset() {
+        let built = IdGenerator::builder()
+            .epoch(1609459200000)
+            .epoch_bits(36)
+            .node_bits(13)
+            .shard_bits(2)
+            .config_id(1)
+            .build()
+            .unwrap();
+        let custom = IdGenerator::new(
+            ConfigPreset::Custom(1609459200000, 36, 13, 2, 10, 1),
+            1609459200000,
+        );
+
+        assert_eq!(built.epoch, custom.epoch);
+        assert_eq!(built.epoch_bits, custom.epoch_bits);
+        assert_eq!(built.node_bits, custom.node_bits);
+        assert_eq!(built.shard_bits, custom.shard_bits);
+        assert_eq!(built.max_nodes, custom.max_nodes);
+        assert_eq!(built.config_id, custom.config_id);
+
+        let id = built.derive_sharded_id(built.next_id(NodeId(5)), 3);
+        let from_built = built.decode_id(id);
+        let from_custom = custom.decode_id(id);
+        assert_eq!(from_built.to_string(), from_custom.to_string());
+
+        let default = IdGeneratorBuilder::new().build().unwrap();
+        let preset = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
+        assert_eq!(default.epoch, preset.epoch);
+        assert_eq!(default.epoch_bits, preset.epoch_bits);
+        assert_eq!(default.node_bits, preset.node_bits);
+        assert_eq!(default.shard_bits, preset.shard_bits);
+        assert_eq!(default.config_id, preset.config_id);
+    }
+
+    #[test]
+    fn test_config_id_range() {
+        assert!(matches!(
+            IdGenerator::try_new(
+                ConfigPreset::Custom(DEFAULT_EPOCH, 36, 13, 2, 10, 8),
+                DEFAULT_EPOCH
+            ),
+            Err(ConfigError::ConfigIdTooLarge {
+                config_id: 8,
+                config_bits: 3
+            })
+        ));
+        assert!(matches!(
+            IdGenerator::builder().config_id(9).build(),
+            Err(ConfigError::ConfigIdTooLarge {
+                config_id: 9,
+                config_bits: 3
+            })
+        ));
+        assert!(IdGenerator::builder().config_id(7).build().is_ok());
+    }
+
+    #[test]
+    fn test_time_field_at_top_of_word() {
+        // 3 + 10 + 1 + 50 bits fills the id exactly
+        let max_time = (1u64 << 50) - 1;
+        let clock = MockClock::new(DEFAULT_EPOCH + (1 << 49));
+        let mut gen = IdGenerator::try_new(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 50, 1, 0, 10, 2),
+            DEFAULT_EPOCH,
+        )
+        .unwrap();
+        gen.clock = Arc::new(clock.clone());
+        assert_eq!(gen.max_timestamp_millis(), max_time);
+        assert_eq!(gen.decode_id(gen.next_id(NodeId(0))).time, 1 << 49);
+
+        clock.set(DEFAULT_EPOCH + max_time);
+        let id = gen.next_id(NodeId(1));
+        assert_eq!(id >> 63, 1);
+        let decoded = gen.decode_id(id);
+        assert_eq!(decoded.time, max_time);
+        assert_eq!(decoded.node_id, 1);
+
+        // One more time bit no longer fits
+        assert!(matches!(
+            IdGenerator::try_new(
+                ConfigPreset::Custom(DEFAULT_EPOCH, 51, 1, 0, 10, 2),
+                DEFAULT_EPOCH
+            ),
+            Err(ConfigError::BitBudgetExceeded { overflow: 1, .. })
+        ));
+
+        // A time field as wide as the whole word masks without overflowing
+        let config = IdConfig {
+            epoch: 0,
+            epoch_bits: 64,
+            node_bits: 0,
+            shard_bits: 0,
+            config_id: 0,
+            config_bits: 0,
+            inc_bits: 0,
+            shard_checksum_bits: 0,
+            shard2_bits: 0,
+            salt_bits: 0,
+        };
+        assert_eq!(config.encode(u64::MAX, 0, 0), u64::MAX);
+        assert_eq!(config.decode(u64::MAX).time, u64::MAX);
+    }
+
+    #[test]
+    fn test_wide_sequence_field() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let gen = IdGenerator::builder()
+            .epoch_bits(41)
+            .node_bits(6)
+            .shard_bits(2)
+            .inc_bits(12)
+            .config_id(2)
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+
+        // 4096 ids fit in one frozen millisecond
+        let ids = gen.next_ids(NodeId(9), 4096);
+        let decoded: Vec<DecodedId> = ids.iter().map(|&id| gen.decode_id(id)).collect();
+        assert!(decoded.iter().all(|d| d.time == 5000));
+        let sequences: HashSet<u64> = decoded.iter().map(|d| d.incrementing_id).collect();
+        assert_eq!(sequences.len(), 4096);
+        assert_eq!(sequences.iter().max(), Some(&4095));
+
+        // Fields round-trip, and the shard sits above the 12-bit sequence
+        let id = gen.derive_sharded_id(ids[100], 3);
+        let d = gen.decode_id(id);
+        assert_eq!((d.node_id, d.shard_id, d.config_id), (9, 3, 2));
+        assert_eq!(d.incrementing_id, decoded[100].incrementing_id);
+        assert_eq!(gen.shard_of(id), 3);
+        assert_eq!((id >> (3 + 12)) & 0b11, 3);
+
+        clock.advance(1);
+        assert_eq!(gen.decode_id(gen.next_id(NodeId(9))).time, 5001);
+
+        // The bigger sequence still counts against the bit budget
+        assert!(matches!(
+            IdGenerator::try_new(
+                ConfigPreset::Custom(DEFAULT_EPOCH, 41, 10, 0, 12, 2),
+                DEFAULT_EPOCH
+            ),
+            Err(ConfigError::BitBudgetExceeded {
+                inc_bits: 12,
+                overflow: 2,
+                ..
+            })
+        ));
+        assert_eq!(
+            IdGenerator::builder()
+                .epoch_bits(30)
+                .inc_bits(17)
+                .build()
+                .err(),
+            Some(ConfigError::SequenceTooWide {
+                inc_bits: 17,
+                max: 16
+            })
+        );
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_next_id_async_waits_for_next_millisecond() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let gen = IdGenerator::with_clock(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 41, 10, 0, 10, 2),
+            DEFAULT_EPOCH,
+            clock.clone(),
+        );
+        let issued: HashSet<u64> = gen.next_ids(NodeId(1), 1024).into_iter().collect();
+        assert_eq!(gen.remaining_in_window(), 0);
+
+        let (id, ()) = tokio::join!(gen.next_id_async(NodeId(1)), async {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            clock.advance(1);
+        });
+        assert!(!issued.contains(&id));
+        assert_eq!(gen.decode_id(id).time, 5001);
+        assert_eq!(gen.try_next_id_async(wide_node(1024)).await.ok(), None);
+    }
+
+    #[test]
+    fn test_decoded_id_bytes_round_trip() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..1000 {
+            let decoded = DecodedId {
+                time: rng.gen(),
+                node_id: rng.gen(),
+                shard_id: rng.gen(),
+                incrementing_id: rng.gen(),
+                config_id: rng.gen(),
+            };
+            assert_eq!(DecodedId::from_bytes(&decoded.to_bytes()), decoded);
+        }
+
+        let decoded = DecodedId {
+            time: 0x0102030405060708,
+            node_id: 9,
+            shard_id: 0x0a0b,
+            incrementing_id: 12,
+            config_id: 13,
+        };
+        let mut expected = [0; DecodedId::BYTES];
+        expected[..8].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        expected[15] = 9;
+        expected[16..18].copy_from_slice(&[0x0a, 0x0b]);
+        expected[25] = 12;
+        expected[26] = 13;
+        assert_eq!(decoded.to_bytes(), expected);
+
+        let gen = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        let id = gen.derive_sharded_id(gen.next_id(NodeId(77)), 5);
+        let bytes = gen.id_to_bytes(id);
+        assert_eq!(DecodedId::from_bytes(&bytes), gen.decode_id(id));
+        assert_eq!(gen.id_from_bytes(&bytes), Ok(id));
+        assert_eq!(
+            gen.id_from_bytes(&decoded.to_bytes()),
+            Err(ReencodeError::FieldTooWide {
+                field: "time",
+                value: 0x0102030405060708,
+                bits: 32
+            })
+        );
+    }
+
+    #[test]
+    fn test_shard_checksum_catches_flipped_shard_bits() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let gen = IdGenerator::builder()
+            .epoch_bits(37)
+            .node_bits(5)
+            .shard_bits(5)
+            .shard_checksum_bits(4)
+            .clock(clock)
+            .build()
+            .unwrap();
+        assert_eq!(gen.layout().total_bits(), 64);
+
+        let original = gen.next_id(NodeId(3));
+        assert!(gen.verify_shard_checksum(original));
+        for shard in 0..32 {
+            let id = gen.derive_sharded_id(original, shard);
+            assert!(gen.verify_shard_checksum(id));
+            let decoded = gen.try_decode_id(id).unwrap();
+            assert_eq!((decoded.shard_id, decoded.time), (shard, 5000));
+            // Resharding replaces the old checksum
+            assert!(gen.verify_shard_checksum(gen.reshard(id, 31 - shard).unwrap()));
+
+            for bit in 0..5 {
+                let flipped = id ^ (1 << (13 + bit));
+                assert!(!gen.verify_shard_checksum(flipped));
+                assert_eq!(
+                    gen.try_decode_id(flipped),
+                    Err(DecodeError::ShardChecksumMismatch)
+                );
+            }
+        }
+
+        // Without the option every id passes, and the budget counts the bits
+        let plain = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        assert!(plain.verify_shard_checksum(u64::MAX));
+        assert!(matches!(
+            IdGenerator::builder()
+                .epoch_bits(37)
+                .node_bits(9)
+                .shard_bits(5)
+                .shard_checksum_bits(4)
+                .build(),
+            Err(ConfigError::BitBudgetExceeded {
+                shard_checksum_bits: 4,
+                overflow: 4,
+                ..
+            })
+        ));
+        assert_eq!(
+            IdGenerator::builder()
+                .shard_checksum_bits(4)
+                .epoch_bits(30)
+                .build()
+                .err(),
+            Some(ConfigError::ZeroWidthField("shard_bits"))
+        );
+    }
+
+    #[test]
+    fn test_layout_getters() {
+        let presets = [
+            (ConfigPreset::ShortEpochMaxNodes, (37, 14, 0, 16384, 3)),
+            (ConfigPreset::ShardedConfig, (32, 14, 5, 16384, 1)),
+            (ConfigPreset::ShardedConfigWide, (41, 5, 5, 32, 4)),
+            (ConfigPreset::Snowflake, (41, 10, 0, 1024, 0)),
+        ];
+        for (preset, expected) in presets {
+            let gen = IdGenerator::new(preset, DEFAULT_EPOCH);
+            assert_eq!(gen.epoch(), DEFAULT_EPOCH);
+            assert_eq!(
+                (
+                    gen.epoch_bits(),
+                    gen.node_bits(),
+                    gen.shard_bits(),
+                    gen.max_nodes(),
+                    gen.config_id()
+                ),
+                expected
+            );
+        }
+
+        // Custom layouts carry their own epoch
+        let custom = IdGenerator::new(ConfigPreset::Custom(1234, 36, 13, 2, 10, 5), DEFAULT_EPOCH);
+        assert_eq!(custom.epoch(), 1234);
+        assert_eq!(custom.config_id(), 5);
+        assert_eq!(custom.max_nodes(), 8192);
+    }
+
+    #[test]
+    fn test_supports_sharding() {
+        let short = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
+        assert!(!short.supports_sharding());
+        assert_eq!(short.shard_bits(), 0);
+
+        let sharded = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        assert!(sharded.supports_sharding());
+        assert_eq!(sharded.shard_bits(), 5);
+        assert!(
+            IdGenerator::new(ConfigPreset::ShardedConfigWide, DEFAULT_EPOCH).supports_sharding()
+        );
+    }
+
+    #[test]
+    fn test_spaces_disjoint() {
+        let sharded = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        let short = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
+        assert!(sharded.spaces_disjoint(&short));
+        assert!(short.spaces_disjoint(&sharded));
+
+        let twin = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        assert!(!sharded.spaces_disjoint(&twin));
+        assert!(!sharded.spaces_disjoint(&sharded));
+
+        // Snowflakes have no config bits to tell them apart by
+        let snowflake = IdGenerator::new(ConfigPreset::Snowflake, DEFAULT_EPOCH);
+        assert!(!snowflake.spaces_disjoint(&sharded));
+        let bare = IdGenerator::builder()
+            .with_config_field(false)
+            .build()
+            .unwrap();
+        assert!(!bare.spaces_disjoint(&short));
+    }
+
+    #[test]
+    fn test_gen_id_error_wraps_each_error() {
+        use std::error::Error;
+
+        let errors: [(GenIdError, String); 6] = [
+            (
+                GenError::ClockWentBackwards.into(),
+                GenError::ClockWentBackwards.to_string(),
+            ),
+            (
+                ConfigError::ZeroWidthField("node_bits").into(),
+                ConfigError::ZeroWidthField("node_bits").to_string(),
+            ),
+            (
+                DecodeError::InvalidCharacter('!').into(),
+                DecodeError::InvalidCharacter('!').to_string(),
+            ),
+            (
+                ShardError::ShardingUnsupported.into(),
+                ShardError::ShardingUnsupported.to_string(),
+            ),
+            (
+                ReencodeError::BeforeEpoch {
+                    timestamp_millis: 1,
+                    epoch: 2,
+                }
+                .into(),
+                ReencodeError::BeforeEpoch {
+                    timestamp_millis: 1,
+                    epoch: 2,
+                }
+                .to_string(),
+            ),
+            (
+                SelfTestError::Layout(ConfigError::ZeroWidthField("epoch_bits")).into(),
+                SelfTestError::Layout(ConfigError::ZeroWidthField("epoch_bits")).to_string(),
+            ),
+        ];
+        for (err, inner) in errors {
+            let message = err.to_string();
+            assert!(message.len() > inner.len());
+            assert!(message.ends_with(&inner));
+            assert_eq!(err.source().map(|source| source.to_string()), Some(inner));
+        }
+
+        // `?` converts each error on the way out
+        fn build_and_shard() -> Result<u64, GenIdError> {
+            let gen = IdGenerator::builder().shard_bits(0).build()?;
+            Ok(gen.try_derive_sharded_id(gen.try_next_id(NodeId(1))?, 1)?)
+        }
+        assert_eq!(
+            build_and_shard(),
+            Err(GenIdError::Shard(ShardError::ShardingUnsupported))
+        );
+        let boxed: Box<dyn Error> = Box::new(GenIdError::from(GenError::ClockWentBackwards));
+        assert!(boxed.source().is_some());
+    }
+
+    #[test]
+    fn test_16_bit_sequence_fills_frozen_millisecond() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let gen = IdGenerator::builder()
+            .epoch_bits(35)
+            .node_bits(10)
+            .inc_bits(16)
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+        assert_eq!(gen.remaining_in_window(), 65536);
+
+        let ids = gen.next_ids(NodeId(3), 65536);
+        let sequences: HashSet<u64> = ids
+            .iter()
+            .map(|&id| {
+                let decoded = gen.decode_id(id);
+                assert_eq!((decoded.time, decoded.node_id), (5000, 3));
+                decoded.incrementing_id
+            })
+            .collect();
+        assert_eq!(sequences.len(), 65536);
+        assert_eq!(gen.remaining_in_window(), 0);
+        assert_eq!(gen.current_sequence(), 65536);
+
+        // The counter runs on past 16 bits and the next window starts from it
+        clock.advance(1);
+        let next = gen.decode_id(gen.next_id(NodeId(3)));
+        assert_eq!((next.time, next.incrementing_id), (5001, 0));
+        assert_eq!(gen.current_sequence(), 65537);
+        assert_eq!(gen.self_test(), Ok(()));
+    }
+
+    #[test]
+    fn test_self_test() {
+        for preset in [
+            ConfigPreset::ShortEpochMaxNodes,
+            ConfigPreset::ShardedConfig,
+            ConfigPreset::Snowflake,
+            ConfigPreset::Custom(DEFAULT_EPOCH, 36, 13, 2, 10, 5),
+            ConfigPreset::Custom(DEFAULT_EPOCH, 31, 16, 0, 10, 2),
+            ConfigPreset::Custom(DEFAULT_EPOCH, 50, 1, 0, 10, 2),
+        ] {
+            let gen = IdGenerator::new(preset, DEFAULT_EPOCH);
+            assert_eq!(gen.self_test(), Ok(()));
+            assert_eq!(gen.current_sequence(), 0);
+        }
+
+        // `new` doesn't validate, so this 77 bit layout only fails here
+        let overflowing = IdGenerator::new(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 45, 14, 5, 10, 2),
+            DEFAULT_EPOCH,
+        );
+        assert!(matches!(
+            overflowing.self_test(),
+            Err(SelfTestError::Layout(ConfigError::BitBudgetExceeded {
+                overflow: 13,
+                ..
+            }))
+        ));
+
+        let bad_config_id = IdGenerator::new(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 36, 13, 2, 10, 9),
+            DEFAULT_EPOCH,
+        );
+        assert_eq!(
+            bad_config_id.self_test(),
+            Err(SelfTestError::Layout(ConfigError::ConfigIdTooLarge {
+                config_id: 9,
+                config_bits: 3
+            }))
+        );
+    }
+
+    #[test]
+    fn test_decode_auto_detects_preset() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        for preset in [
+            ConfigPreset::ShardedConfig,
+            ConfigPreset::ShortEpochMaxNodes,
+            ConfigPreset::ShardedConfigWide,
+        ] {
+            let gen = IdGenerator::with_clock(preset, DEFAULT_EPOCH, clock.clone());
+            gen.next_id(NodeId(7));
+            let id = gen.next_id(NodeId(7));
+            assert_eq!(DecodedId::from_id_auto(id), Ok(gen.decode_id(id)));
+            assert_eq!(DecodedId::try_from(id), Ok(gen.decode_id(id)));
+        }
+
+        let sharded =
+            IdGenerator::with_clock(ConfigPreset::ShardedConfig, DEFAULT_EPOCH, clock.clone());
+        let id = sharded.derive_sharded_id(sharded.next_id(NodeId(7)), 4);
+        assert_eq!(DecodedId::from_id_auto(id).unwrap().shard_id, 4);
+
+        let custom = IdGenerator::with_clock(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 41, 10, 0, 10, 2),
+            DEFAULT_EPOCH,
+            clock,
+        );
+        assert_eq!(
+            DecodedId::from_id_auto(custom.next_id(NodeId(7))),
+            Err(DecodeError::UnknownConfig(2))
+        );
+    }
+
+    #[test]
+    fn test_layout_without_config_field() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let build = |config_field| {
+            IdGenerator::builder()
+                .epoch_bits(41)
+                .node_bits(10)
+                .with_config_field(config_field)
+                .clock(clock.clone())
+                .build()
+                .unwrap()
+        };
+        let with_config = build(true);
+        let without = build(false);
+        assert_eq!(with_config.config_id, 3);
+        assert_eq!(without.config_id, 0);
+
+        without.next_ids(NodeId(12), 5);
+        with_config.next_ids(NodeId(12), 5);
+        let bare = without.next_id(NodeId(12));
+        let tagged = with_config.next_id(NodeId(12));
+
+        // The sequence sits at bit 0
+        assert_eq!(bare, (5000 << 20) | (12 << 10) | 5);
+        let decoded = without.decode_id(bare);
+        assert_eq!(
+            (decoded.time, decoded.node_id, decoded.incrementing_id),
+            (5000, 12, 5)
+        );
+        assert_eq!(decoded.config_id, 0);
+        assert_eq!(without.try_decode_id(bare), Ok(decoded));
+
+        // Same fields, three bits fewer
+        assert_eq!(bare, tagged >> 3);
+        assert_eq!(without.layout().total_bits(), 61);
+
+        // Nothing else fits in a field with no bits
+        assert_eq!(
+            IdGenerator::builder()
+                .with_config_field(false)
+                .config_id(1)
+                .build()
+                .err(),
+            Some(ConfigError::ConfigIdTooLarge {
+                config_id: 1,
+                config_bits: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_zero_width_fields_rejected() {
+        assert_eq!(
+            IdGenerator::try_new(
+                ConfigPreset::Custom(DEFAULT_EPOCH, 0, 14, 0, 10, 2),
+                DEFAULT_EPOCH
+            )
+            .err(),
+            Some(ConfigError::ZeroWidthField("epoch_bits"))
+        );
+        assert_eq!(
+            IdGenerator::try_new(
+                ConfigPreset::Custom(DEFAULT_EPOCH, 41, 0, 0, 10, 2),
+                DEFAULT_EPOCH
+            )
+            .err(),
+            Some(ConfigError::ZeroWidthField("node_bits"))
+        );
+        assert_eq!(
+            IdGenerator::builder()
+                .epoch_bits(0)
+                .node_bits(0)
+                .build()
+                .err(),
+            Some(ConfigError::ZeroWidthField("epoch_bits"))
+        );
+        assert_eq!(
+            IdGenerator::builder().node_bits(0).build().err(),
+            Some(ConfigError::ZeroWidthField("node_bits"))
+        );
+
+        // Shards are optional, so zero shard bits is still fine
+        assert!(IdGenerator::builder().shard_bits(0).build().is_ok());
+    }
+
+    #[test]
+    fn test_builder_rejects_invalid_layout() {
+        let result = IdGenerator::builder().epoch_bits(45).shard_bits(5).build();
+        assert!(matches!(
+            result,
+            Err(ConfigError::BitBudgetExceeded { overflow: 13, .. })
+        ));
+    }
+
+    #[test]
+    fn test_config_json_round_trip() {
+        let gen = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        let config = IdConfig::from(&gen);
+
+        let json = serde_json::to_string(&config).unwrap();
+        assert_eq!(
+            json,
+            r#"{"epoch":1609459200000,"epoch_bits":32,"node_bits":14,"shard_bits":5,"config_id":1,"config_bits":3,"inc_bits":10}"#
+        );
+
+        let restored: IdConfig = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, config);
+
+        // Configs saved without the fixed field widths still load
+        let legacy: IdConfig = serde_json::from_str(
+            r#"{"epoch":1609459200000,"epoch_bits":32,"node_bits":14,"shard_bits":5,"config_id":1}"#,
+        )
+        .unwrap();
+        assert_eq!(legacy, config);
+
+        let reloaded = IdGenerator::from_config(restored).unwrap();
+        for node_id in [0, 9, 16383] {
+            let id = gen.derive_sharded_id(gen.next_id(NodeId(node_id)), 17);
+            assert_eq!(
+                reloaded.decode_id(id).to_string(),
+                gen.decode_id(id).to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn test_decode_known_snowflake() {
+        // The example from Discord's API docs
+        const DISCORD_EPOCH: u64 = 1420070400000;
+        let decoded = decode_snowflake(175928847299117063);
+
+        assert_eq!(decoded.timestamp_millis(DISCORD_EPOCH), 1462015105796);
+        // Worker 1, process 0
+        assert_eq!(decoded.node_id, 1 << 5);
+        assert_eq!(decoded.incrementing_id, 7);
+        assert_eq!(decoded.config_id, 0);
+        assert_eq!(decoded.shard_id, 0);
+    }
+
+    #[test]
+    fn test_snowflake_preset() {
+        const TWITTER_EPOCH: u64 = 1288834974657;
+        let clock = MockClock::new(TWITTER_EPOCH + 123_456_789);
+        let gen = IdGenerator::with_clock(ConfigPreset::Snowflake, TWITTER_EPOCH, clock.clone());
+
+        let id = gen.next_id(NodeId(1023));
+        assert_eq!(id, (123_456_789 << 22) | (1023 << 12));
+        assert!(id < 1 << 63);
+        assert_eq!(gen.decode_id(id), decode_snowflake(id));
+        assert_eq!(gen.try_decode_id(id), Ok(decode_snowflake(id)));
+        assert!(matches!(
+            gen.try_next_id(wide_node(1024)),
+            Err(GenError::NodeIdOutOfRange {
+                max_nodes: 1024,
+                ..
+            })
+        ));
+
+        // The 12-bit sequence allows 4096 ids per millisecond
+        let ids: HashSet<u64> = (1..4096).map(|_| gen.next_id(NodeId(5))).collect();
+        assert_eq!(ids.len(), 4095);
+        assert!(ids.iter().all(|&id| gen.decode_id(id).time == 123_456_789));
+        assert!(!ids.contains(&id));
+
+        clock.advance(1);
+        assert_eq!(gen.decode_id(gen.next_id(NodeId(5))).time, 123_456_790);
+    }
+
+    #[test]
+    fn test_capacity_headroom() {
+        const YEAR_MILLIS: u64 = 365 * 24 * 60 * 60 * 1000;
+
+        let short = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
+        assert_eq!(short.max_timestamp_millis(), (1 << 37) - 1);
+        assert_eq!(short.max_node_count(), 16384);
+        assert_eq!(short.max_shard_count(), 1);
+
+        let range = short
+            .epoch_exhaustion_date()
+            .duration_since(UNIX_EPOCH + Duration::from_millis(DEFAULT_EPOCH))
+            .unwrap()
+            .as_millis() as u64;
+        assert!(range > 4 * YEAR_MILLIS && range < 5 * YEAR_MILLIS);
+
+        let sharded = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        assert_eq!(sharded.max_shard_count(), 32);
+        // 32 bits of milliseconds is under two months
+        assert!(sharded.max_timestamp_millis() < 50 * 24 * 60 * 60 * 1000);
+
+        let wide = IdGenerator::new(ConfigPreset::ShardedConfigWide, DEFAULT_EPOCH);
+        assert_eq!(wide.max_shard_count(), 32);
+        assert_eq!(wide.max_node_count(), 32);
+        let narrow_end = sharded.epoch_exhaustion_date();
+        let wide_end = wide.epoch_exhaustion_date();
+        let extra = wide_end.duration_since(narrow_end).unwrap().as_millis() as u64;
+        assert!(extra > 69 * YEAR_MILLIS);
+        // Past the 2021 epoch plus 69 years, so it hasn't wrapped
+        assert!(wide_end > SystemTime::now());
+        assert!(IdGenerator::try_new(ConfigPreset::ShardedConfigWide, DEFAULT_EPOCH).is_ok());
+
+        // An unchecked layout whose time field fills the id ends at the last
+        // representable millisecond rather than overflowing past it
+        let whole_word = IdGenerator::with_layout(IdConfig {
+            epoch: DEFAULT_EPOCH,
+            epoch_bits: 64,
+            node_bits: 0,
+            shard_bits: 0,
+            config_id: 0,
+            config_bits: 0,
+            inc_bits: 0,
+            shard_checksum_bits: 0,
+            shard2_bits: 0,
+            salt_bits: 0,
+        });
+        assert_eq!(
+            whole_word.epoch_exhaustion_date(),
+            UNIX_EPOCH + Duration::from_millis(u64::MAX)
+        );
+        let decoded = whole_word.decode_id(u64::MAX);
+        assert_eq!(decoded.time, u64::MAX);
+        assert_eq!(decoded.timestamp_millis(DEFAULT_EPOCH), u64::MAX);
+        assert_eq!(
+            whole_word.validate(u64::MAX, DEFAULT_EPOCH, 0),
+            Err(ValidationError::TimeInFuture {
+                timestamp_millis: u64::MAX,
+                limit: DEFAULT_EPOCH
+            })
+        );
+    }
+
+    #[test]
+    fn test_next_id_at_backfills_history() {
+        const DAY_MILLIS: u64 = 24 * 60 * 60 * 1000;
+
+        let clock = MockClock::new(DEFAULT_EPOCH + 400 * DAY_MILLIS);
+        let gen = IdGenerator::with_clock(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 41, 10, 0, 10, 2),
+            DEFAULT_EPOCH,
+            clock,
+        );
+        let past = DEFAULT_EPOCH + 30 * DAY_MILLIS + 1234;
+        let first = gen.next_id_at(NodeId(3), past).unwrap();
+        let second = gen.next_id_at(NodeId(3), past).unwrap();
+        assert_ne!(first, second);
+        for id in [first, second] {
+            let decoded = gen.decode_id(id);
+            assert_eq!(decoded.timestamp_millis(DEFAULT_EPOCH), past);
+            assert_eq!(decoded.node_id, 3);
+        }
+        // Live ids keep using the clock
+        assert_eq!(gen.decode_id(gen.next_id(NodeId(3))).time, 400 * DAY_MILLIS);
+
+        assert_eq!(
+            gen.next_id_at(NodeId(3), DEFAULT_EPOCH - 1),
+            Err(GenError::ClockBehindEpoch {
+                epoch: DEFAULT_EPOCH,
+                now: DEFAULT_EPOCH - 1
+            })
+        );
+        assert!(matches!(
+            gen.next_id_at(wide_node(1024), past),
+            Err(GenError::NodeIdOutOfRange { .. })
+        ));
+
+        let sharded = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        let last = DEFAULT_EPOCH + sharded.max_timestamp_millis();
+        assert!(sharded.next_id_at(NodeId(3), last).is_ok());
+        assert_eq!(
+            sharded.next_id_at(NodeId(3), last + 1),
+            Err(GenError::TimestampOutOfRange {
+                timestamp_millis: last + 1,
+                max: last
+            })
+        );
+    }
+
+    #[test]
+    fn test_next_id_at_shares_the_live_window() {
+        let now = DEFAULT_EPOCH + 5000;
+        let clock = MockClock::new(now);
+        let gen = IdGenerator::with_clock(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 41, 10, 0, 10, 2),
+            DEFAULT_EPOCH,
+            clock.clone(),
+        );
+
+        // Live and backfilled ids split the live millisecond between them
+        let mut ids = gen.next_ids(NodeId(1), 1000);
+        for _ in 0..24 {
+            ids.push(gen.next_id_at(NodeId(1), now).unwrap());
+        }
+        let unique: HashSet<u64> = ids.iter().copied().collect();
+        assert_eq!(unique.len(), 1024);
+        assert!(ids.iter().all(|&id| gen.decode_id(id).time == 5000));
+        assert_eq!(
+            gen.next_id_at(NodeId(1), now),
+            Err(GenError::BackfillExhausted {
+                timestamp_millis: now
+            })
+        );
+
+        // Once the window moves on, its millisecond is live history
+        clock.advance(1);
+        gen.next_id(NodeId(1));
+        for millis in [now, now + 2] {
+            assert_eq!(
+                gen.next_id_at(NodeId(1), millis),
+                Err(GenError::BackfillOverlapsLive {
+                    timestamp_millis: millis,
+                    live_since: now
+                })
+            );
+        }
+
+        // Milliseconds before live ids began have a sequence of their own
+        let before: HashSet<u64> = (0..1024)
+            .map(|_| gen.next_id_at(NodeId(1), now - 1).unwrap())
+            .collect();
+        assert_eq!(before.len(), 1024);
+        assert_eq!(
+            gen.next_id_at(NodeId(1), now - 1),
+            Err(GenError::BackfillExhausted {
+                timestamp_millis: now - 1
+            })
+        );
+        assert!(gen.next_id_at(NodeId(2), now - 1).is_ok());
+    }
+
+    #[test]
+    fn test_max_ids_per_second() {
+        let short = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
+        assert_eq!(short.max_ids_per_second_per_node(), 1024 * 1000);
+        assert_eq!(short.max_ids_per_second_total(), 1024 * 1000 * 16384);
+
+        let snowflake = IdGenerator::new(ConfigPreset::Snowflake, DEFAULT_EPOCH);
+        assert_eq!(snowflake.max_ids_per_second_per_node(), 4096 * 1000);
+        assert_eq!(snowflake.max_ids_per_second_total(), 4096 * 1000 * 1024);
+
+        // 2^50 nodes at 1,024,000 ids a second each is past u64::MAX
+        let wide_nodes = IdGenerator::builder()
+            .epoch_bits(1)
+            .node_bits(50)
+            .inc_bits(10)
+            .build()
+            .unwrap();
+        assert_eq!(wide_nodes.max_ids_per_second_per_node(), 1024 * 1000);
+        assert_eq!(wide_nodes.max_ids_per_second_total(), u64::MAX);
+
+        let wide_sequence = IdGenerator::new(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 0, 0, 0, 64, 0),
+            DEFAULT_EPOCH,
+        );
+        assert_eq!(wide_sequence.max_ids_per_second_per_node(), u64::MAX);
+        assert_eq!(wide_sequence.max_ids_per_second_total(), u64::MAX);
+    }
+
+    #[test]
+    fn test_node_for_key() {
+        let gen = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        let node = gen.node_for_key(b"user:42");
+        assert_eq!(node.get(), 8642);
+        assert!((0..10).all(|_| gen.node_for_key(b"user:42") == node));
+        assert_eq!(gen.decode_id(gen.next_id(node)).node_id, 8642);
+
+        let small = IdGenerator::new(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 41, 4, 0, 10, 2),
+            DEFAULT_EPOCH,
+        );
+        assert_eq!(small.node_for_key(b"hello").get(), 11);
+        let mut counts = [0u32; 16];
+        for i in 0..16_000 {
+            counts[small.node_for_key(format!("order-{}", i).as_bytes()).get() as usize] += 1;
+        }
+        assert!(
+            counts.iter().all(|&n| (800..1200).contains(&n)),
+            "{:?}",
+            counts
+        );
+    }
+
+    #[test]
+    fn test_genesis_id() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let gen =
+            IdGenerator::with_clock(ConfigPreset::ShardedConfig, DEFAULT_EPOCH, clock.clone());
+        for node in [0, 1, 16383] {
+            let genesis = gen.genesis_id(NodeId(node));
+            let decoded = gen.decode_id(genesis);
+            assert_eq!(decoded.time, 0);
+            assert_eq!(decoded.node_id, node as u64);
+            assert_eq!((decoded.shard_id, decoded.incrementing_id), (0, 0));
+            assert_eq!(decoded.timestamp_millis(gen.epoch()), DEFAULT_EPOCH);
+            assert!(genesis < gen.next_id(NodeId(node)));
+            assert_eq!(gen.validate(genesis, DEFAULT_EPOCH, 0), Ok(()));
+        }
+        assert_eq!(gen.genesis_id(NodeId(0)), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Node id 16384 exceeds maximum")]
+    fn test_genesis_id_panics_for_out_of_range_node() {
+        let gen = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        gen.genesis_id(wide_node(16384));
+    }
+
+    #[test]
+    fn test_capacity_in_window() {
+        let gen = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        let start = DEFAULT_EPOCH + 60_000;
+        assert_eq!(gen.capacity_in_window(start, start + 1000), 1024 * 1000);
+        assert_eq!(
+            gen.capacity_in_window(start, start + 1000),
+            gen.max_ids_per_second_per_node()
+        );
+        assert_eq!(gen.capacity_in_window(start, start), 0);
+        assert_eq!(gen.capacity_in_window(start + 1000, start), 0);
+
+        // Only the time the id space covers counts
+        assert_eq!(
+            gen.capacity_in_window(DEFAULT_EPOCH - 500, DEFAULT_EPOCH + 500),
+            1024 * 500
+        );
+        let last = DEFAULT_EPOCH + gen.max_timestamp_millis();
+        assert_eq!(gen.capacity_in_window(last, last + 1000), 1024);
+
+        let snowflake = IdGenerator::new(ConfigPreset::Snowflake, DEFAULT_EPOCH);
+        assert_eq!(
+            snowflake.capacity_in_window(start, start + 1000),
+            4096 * 1000
+        );
+    }
+
+    #[test]
+    fn test_id_uniqueness_and_sequence() {
+        let gen = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
+        let mut last_id = 0;
+        for _ in 0..100 {
+            let new_id = gen.next_id(NodeId(1));
+            assert!(new_id > last_id);
+            last_id = new_id;
+        }
+    }
+
+    #[test]
+    fn test_id_composition() {
+        let gen = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
+        let id = gen.next_id(NodeId(1));
+        let decoded = gen.decode_id(id);
+        assert_eq!(decoded.config_id, 3);
+        assert!(
+            decoded.time
+                <= (SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis() as u64
+                    - DEFAULT_EPOCH)
+        );
+        assert_eq!(decoded.node_id, 1);
+    }
+
+    #[test]
+    fn test_decoded_system_time() {
+        // The presets' time fields have already wrapped since DEFAULT_EPOCH,
+        // so use a layout wide enough to hold the current time
+        let gen = IdGenerator::new(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 41, 10, 0, 10, 2),
+            DEFAULT_EPOCH,
+        );
+        let before = SystemTime::now();
+        let decoded = gen.decode_id(gen.next_id(NodeId(1)));
+
+        let issued = decoded.system_time(DEFAULT_EPOCH);
+        let skew = match issued.duration_since(before) {
+            Ok(ahead) => ahead,
+            Err(behind) => behind.duration(),
+        };
+        assert!(skew < Duration::from_millis(50), "skew was {:?}", skew);
+
+        assert_eq!(
+            decoded.timestamp_millis(DEFAULT_EPOCH),
+            DEFAULT_EPOCH + decoded.time
+        );
+    }
+
+    #[test]
+    fn test_boundary_conditions() {
+        let gen = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
+        // Test maximum node ID
+        let max_node_id = gen.max_nodes - 1;
+        let id = gen.next_id(NodeId(max_node_id));
+        let decoded = gen.decode_id(id);
+        assert_eq!(decoded.node_id, max_node_id as u64);
+
+        // Test maximum incrementing ID
+        let max_inc_id = (1 << INC_BITS) - 1; // 10 bits max
+        let id = gen.generate_id(0, 1, max_inc_id);
+        let decoded = gen.decode_id(id);
+        assert_eq!(decoded.incrementing_id, max_inc_id);
+    }
+
+    #[test]
+    fn test_decode_correctness() {
+        let gen = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
+        let id = gen.next_id(NodeId(1));
+        let decoded = gen.decode_id(id);
+        assert_eq!(decoded.node_id, 1);
+        assert_eq!(decoded.config_id, 3);
+    }
+
+    #[test]
+    fn test_sequence_rollover_uniqueness() {
+        let gen = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
+        let mut seen = HashSet::new();
+        for _ in 0..20_000 {
+            let id = gen.next_id(NodeId(1));
+            assert!(seen.insert(id), "Duplicate id {} issued", id);
+        }
+    }
+
+    #[test]
+    fn test_encode_decode_round_trip() {
+        let mut rng = rand::thread_rng();
+        let presets = [
+            ConfigPreset::ShortEpochMaxNodes,
+            ConfigPreset::ShardedConfig,
+            ConfigPreset::Custom(DEFAULT_EPOCH, 36, 13, 2, 10, 5),
+        ];
+
+        for preset in presets {
+            let gen = IdGenerator::new(preset, DEFAULT_EPOCH);
+            for _ in 0..1000 {
+                let time = rng.gen_range(0..(1u64 << gen.epoch_bits));
+                let node_id = rng.gen_range(0..gen.max_nodes);
+                let inc = rng.gen_range(0..(1u64 << INC_BITS));
+
+                let decoded = gen.decode_id(gen.generate_id(time, node_id, inc));
+                assert_eq!(decoded.time, time);
+                assert_eq!(decoded.node_id, node_id as u64);
+                assert_eq!(decoded.incrementing_id, inc);
+                assert_eq!(decoded.shard_id, 0);
+                assert_eq!(decoded.config_id, gen.config_id);
+            }
+        }
+    }
+
+    #[test]
+    fn test_base62_round_trip() {
+        assert_eq!(encode_base62(0), "0");
+        assert_eq!(decode_base62("0"), Ok(0));
+        assert_eq!(encode_base62(61), "z");
+        assert_eq!(encode_base62(62), "10");
+        assert_eq!(encode_base62(u64::MAX), "LygHa16AHYF");
+        assert_eq!(decode_base62("LygHa16AHYF"), Ok(u64::MAX));
+
+        let gen = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        for _ in 0..100 {
+            let id = gen.next_id(NodeId(1));
+            assert_eq!(decode_base62(&encode_base62(id)), Ok(id));
+        }
+    }
+
+    #[test]
+    fn test_base62_malformed_input() {
+        assert_eq!(decode_base62(""), Err(DecodeError::Empty));
+        assert_eq!(
+            decode_base62("ab-c"),
+            Err(DecodeError::InvalidCharacter('-'))
+        );
+        assert_eq!(decode_base62("é"), Err(DecodeError::InvalidCharacter('é')));
+        assert_eq!(decode_base62("007"), Err(DecodeError::LeadingZero));
+        // One past u64::MAX
+        assert_eq!(decode_base62("LygHa16AHYG"), Err(DecodeError::Overflow));
+        assert_eq!(decode_base62("zzzzzzzzzzzz"), Err(DecodeError::Overflow));
+    }
+
+    #[test]
+    fn test_sortable_encoding_preserves_order() {
+        assert_eq!(encode_sortable(0), "0000000000000");
+        assert_eq!(encode_sortable(u64::MAX), "FZZZZZZZZZZZZ");
+        assert_eq!(decode_sortable("FZZZZZZZZZZZZ"), Ok(u64::MAX));
+
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let gen =
+            IdGenerator::with_clock(ConfigPreset::ShardedConfig, DEFAULT_EPOCH, clock.clone());
+        let mut ids = Vec::new();
+        for step in 0..50 {
+            ids.push(gen.next_id(NodeId(step % 7)));
+            clock.advance(step as u64 % 3);
+        }
+        // Spread across the whole range too, where decimal strings would
+        // sort by length first
+        ids.extend([0, 9, 10, 1 << 40, u64::MAX - 1, u64::MAX]);
+
+        let mut encoded: Vec<String> = ids.iter().map(|&id| encode_sortable(id)).collect();
+        encoded.sort();
+        let decoded: Vec<u64> = encoded
+            .iter()
+            .map(|s| decode_sortable(s).unwrap())
+            .collect();
+        ids.sort_unstable();
+        assert_eq!(decoded, ids);
+    }
+
+    #[test]
+    fn test_crockford_round_trip() {
+        let gen = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        let mut ids: Vec<u64> = (0..100).map(|i| gen.next_id(NodeId(i))).collect();
+        ids.extend([0, 1, 31, 32, 1 << 40, u64::MAX]);
+        for id in ids {
+            let encoded = encode_crockford(id);
+            assert_eq!(encoded.len(), 13);
+            assert_eq!(encoded, encode_sortable(id));
+            assert_eq!(decode_crockford(&encoded), Ok(id));
+            assert_eq!(decode_crockford(&encoded.to_lowercase()), Ok(id));
+        }
+    }
+
+    #[test]
+    fn test_crockford_aliases() {
+        assert_eq!(decode_crockford("0000000000001"), Ok(1));
+        assert_eq!(decode_crockford("000000000000I"), Ok(1));
+        assert_eq!(decode_crockford("000000000000i"), Ok(1));
+        assert_eq!(decode_crockford("000000000000L"), Ok(1));
+        assert_eq!(decode_crockford("000000000000l"), Ok(1));
+        assert_eq!(decode_crockford("OoOoOoOoOoOoZ"), Ok(31));
+        assert_eq!(
+            decode_crockford("FZZZZZZZZZZZZ"),
+            decode_crockford("fzzzzzzzzzzzz")
+        );
+
+        // U stays out, and the strict decoder still rejects the aliases
+        assert_eq!(
+            decode_crockford("000000000000U"),
+            Err(DecodeError::InvalidCharacter('U'))
+        );
+        assert_eq!(
+            decode_crockford("000000000000-"),
+            Err(DecodeError::InvalidCharacter('-'))
+        );
+        assert_eq!(
+            decode_sortable("000000000000L"),
+            Err(DecodeError::InvalidCharacter('L'))
+        );
+        assert_eq!(
+            decode_crockford("g000000000000"),
+            Err(DecodeError::Overflow)
+        );
+        assert_eq!(decode_crockford(""), Err(DecodeError::Empty));
+    }
+
+    #[test]
+    fn test_sortable_malformed_input() {
+        assert_eq!(decode_sortable(""), Err(DecodeError::Empty));
+        assert_eq!(
+            decode_sortable("ZZ"),
+            Err(DecodeError::InvalidLength {
+                expected: 13,
+                found: 2
+            })
+        );
+        // I, L, O and U aren't in the alphabet, nor is lowercase
+        assert_eq!(
+            decode_sortable("000000000000I"),
+            Err(DecodeError::InvalidCharacter('I'))
+        );
+        assert_eq!(
+            decode_sortable("000000000000a"),
+            Err(DecodeError::InvalidCharacter('a'))
+        );
+        assert_eq!(decode_sortable("G000000000000"), Err(DecodeError::Overflow));
+    }
+
+    #[test]
+    fn test_decoded_id_display_round_trip() {
+        let gen = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        let id = gen.derive_sharded_id(gen.next_id(NodeId(7)), 3);
+        let decoded = gen.decode_id(id);
+
+        let text = decoded.to_string();
+        assert_eq!(
+            text,
+            format!(
+                "t={}/n=7/s=3/i={}/c=1",
+                decoded.time, decoded.incrementing_id
+            )
+        );
+
+        let parsed: DecodedId = text.parse().unwrap();
+        assert_eq!(parsed, decoded);
+        assert_eq!(parsed.to_string(), text);
+    }
+
+    #[test]
+    fn test_decoded_ids_in_hash_set() {
+        let gen = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        let id = gen.next_id(NodeId(7));
+        let other = gen.next_id(NodeId(8));
+
+        let mut seen = HashSet::new();
+        assert!(seen.insert(gen.decode_id(id)));
+        assert!(!seen.insert(gen.decode_id(id)));
+        assert!(seen.insert(gen.decode_id(other)));
+        assert_eq!(seen.len(), 2);
+
+        // Shards differ, so the derived id decodes to a distinct value
+        let sharded = gen.decode_id(gen.derive_sharded_id(id, 3));
+        assert!(seen.insert(sharded));
+        assert_ne!(sharded, gen.decode_id(id));
+    }
+
+    #[test]
+    fn test_decoded_id_parse_errors() {
+        use ParseDecodedIdError::*;
+
+        let cases = [
+            ("", MissingField("t")),
+            ("n=1/s=0/i=0/c=1", MissingField("t")),
+            ("t=1", MissingField("n")),
+            ("t=1/n=1/i=0/c=1", MissingField("s")),
+            ("t=1/n=1/s=0/c=1", MissingField("i")),
+            ("t=1/n=1/s=0/i=0", MissingField("c")),
+            ("t=x/n=1/s=0/i=0/c=1", InvalidValue("t")),
+            ("t=1/n=-1/s=0/i=0/c=1", InvalidValue("n")),
+            ("t=1/n=1/s=70000/i=0/c=1", InvalidValue("s")),
+            ("t=1/n=1/s=0/i=/c=1", InvalidValue("i")),
+            ("t=1/n=1/s=0/i=0/c=256", InvalidValue("c")),
+            ("t=1/n=1/s=0/i=0/c=1/x=2", TrailingInput),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(
+                input.parse::<DecodedId>().unwrap_err(),
+                expected,
+                "unexpected result for {:?}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_mock_clock_same_millisecond_sequencing() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 1000);
+        let gen = IdGenerator::with_clock(
+            ConfigPreset::ShortEpochMaxNodes,
+            DEFAULT_EPOCH,
+            clock.clone(),
+        );
+
+        for expected_inc in 0..1024 {
+            let decoded = gen.decode_id(gen.next_id(NodeId(1)));
+            assert_eq!(decoded.time, 1000);
+            assert_eq!(decoded.incrementing_id, expected_inc);
+        }
+
+        clock.advance(1);
+        let decoded = gen.decode_id(gen.next_id(NodeId(1)));
+        assert_eq!(decoded.time, 1001);
+        assert_eq!(decoded.incrementing_id, 0);
+    }
+
+    #[test]
+    fn test_mock_clock_rollover_waits_for_next_millisecond() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 1000);
+        let gen = Arc::new(IdGenerator::with_clock(
+            ConfigPreset::ShortEpochMaxNodes,
+            DEFAULT_EPOCH,
+            clock.clone(),
+        ));
+
+        let first = gen.next_id(NodeId(1));
+        for _ in 1..1024 {
+            gen.next_id(NodeId(1));
+        }
+
+        let reads_before = clock.reads();
+        let handle = thread::spawn({
+            let gen = gen.clone();
+            move || gen.next_id(NodeId(1))
+        });
+
+        // The generator keeps polling the frozen clock while it is spent
+        while clock.reads() < reads_before + 100 {
+            thread::yield_now();
+        }
+        assert!(!handle.is_finished());
+
+        clock.advance(1);
+        let rolled = handle.join().unwrap();
+        let decoded = gen.decode_id(rolled);
+        assert_eq!(decoded.time, 1001);
+        assert_eq!(
+            decoded.incrementing_id,
+            gen.decode_id(first).incrementing_id
+        );
+        assert_ne!(rolled, first);
+    }
+
+    #[test]
+    fn test_concurrent_ids_are_unique() {
+        let gen = Arc::new(IdGenerator::new(
+            ConfigPreset::ShortEpochMaxNodes,
+            DEFAULT_EPOCH,
+        ));
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let gen = gen.clone();
+                thread::spawn(move || {
+                    (0..10_000)
+                        .map(|_| gen.next_id(NodeId(1)))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        let mut seen = HashSet::new();
+        for handle in handles {
+            for id in handle.join().unwrap() {
+                assert!(seen.insert(id), "Duplicate id {} across threads", id);
+            }
+        }
+        assert_eq!(seen.len(), 80_000);
+    }
+
+    #[test]
+    fn test_next_ids_bulk() {
+        let gen = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
+        let ids = gen.next_ids(NodeId(1), 5000);
+        assert_eq!(ids.len(), 5000);
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let times: HashSet<u64> = ids.iter().map(|&id| gen.decode_id(id).time).collect();
+        assert!(
+            times.len() >= 5,
+            "5000 ids must span at least 5 milliseconds"
+        );
+
+        // Bulk ids don't collide with ones issued singly either side
+        let mut seen: HashSet<u64> = ids.into_iter().collect();
+        assert!(seen.insert(gen.next_id(NodeId(1))));
+        for id in gen.next_ids(NodeId(1), 3000) {
+            assert!(seen.insert(id));
+        }
+
+        assert!(gen.next_ids(NodeId(1), 0).is_empty());
+    }
+
+    #[test]
+    fn test_monotonic_mode_survives_clock_regression() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 10_000);
+        let gen = IdGenerator::builder()
+            .clock(clock.clone())
+            .monotonic(50)
+            .build()
+            .unwrap();
+
+        let mut last = gen.next_id(NodeId(1));
+        for _ in 0..5 {
+            let id = gen.next_id(NodeId(1));
+            assert!(id > last);
+            last = id;
+        }
+
+        clock.set(DEFAULT_EPOCH + 9_980);
+        for _ in 0..5 {
+            let id = gen.next_id(NodeId(1));
+            assert!(id > last, "id went backwards with the clock");
+            assert_eq!(gen.decode_id(id).time, 10_000);
+            last = id;
+        }
+
+        clock.set(DEFAULT_EPOCH + 10_001);
+        let id = gen.next_id(NodeId(1));
+        assert!(id > last);
+        assert_eq!(gen.decode_id(id).time, 10_001);
+
+        // Windows start at sequence 0, so a counter past the top of the field
+        // doesn't wrap to a lower id mid-window
+        let mut ids = vec![id];
+        ids.extend(gen.next_ids(NodeId(1), 1000));
+        clock.advance(1);
+        ids.extend(gen.next_ids(NodeId(1), 100));
+        clock.set(DEFAULT_EPOCH + 9_990);
+        ids.extend(gen.next_ids(NodeId(1), 100));
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // Regressions past the tolerance still fail
+        clock.set(DEFAULT_EPOCH + 9_900);
+        assert_eq!(
+            gen.try_next_id(NodeId(1)),
+            Err(GenError::ClockWentBackwards)
+        );
+    }
+
+    #[test]
+    fn test_clock_regression_without_monotonic_mode() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 10_000);
+        let gen = IdGenerator::with_clock(
+            ConfigPreset::ShortEpochMaxNodes,
+            DEFAULT_EPOCH,
+            clock.clone(),
+        );
+        gen.next_id(NodeId(1));
+        clock.set(DEFAULT_EPOCH + 9_999);
+        assert_eq!(
+            gen.try_next_id(NodeId(1)),
+            Err(GenError::ClockWentBackwards)
+        );
+    }
+
+    #[test]
+    fn test_config_id_of() {
+        let presets = [
+            (ConfigPreset::ShortEpochMaxNodes, 3),
+            (ConfigPreset::ShardedConfig, 1),
+            (ConfigPreset::Custom(DEFAULT_EPOCH, 36, 13, 2, 10, 6), 6),
+        ];
+        for (preset, expected) in presets {
+            let gen = IdGenerator::new(preset, DEFAULT_EPOCH);
+            for node_id in [0, 1, 100] {
+                let id = gen.next_id(NodeId(node_id));
+                assert_eq!(config_id_of(id), expected);
+                assert_eq!(gen.config_id_of(id), expected);
+            }
+        }
+
+        // Without a config field the low bits are sequence, not a config id
+        let configless = [
+            IdGenerator::new(ConfigPreset::Snowflake, DEFAULT_EPOCH),
+            IdGenerator::builder()
+                .with_config_field(false)
+                .build()
+                .unwrap(),
+        ];
+        for gen in configless {
+            let ids = gen.next_ids(NodeId(1), 8);
+            assert!(ids.iter().any(|&id| config_id_of(id) != 0));
+            assert!(ids.iter().all(|&id| gen.config_id_of(id) == 0));
+        }
+    }
+
+    #[test]
+    fn test_u128_round_trip() {
+        let gen = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        let before = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+            - DEFAULT_EPOCH;
+
+        let first = gen.decode_u128(gen.next_u128(NodeId(42)));
+        let second = gen.decode_u128(gen.next_u128(NodeId(42)));
+        assert_eq!(first.node_id, 42);
+        assert_eq!(first.config_id, 1);
+        assert_eq!(first.shard_id, 0);
+        // The 48-bit time field holds the full offset from the epoch
+        assert!(first.time >= before && first.time - before < 50);
+        assert_ne!(first.incrementing_id, second.incrementing_id);
+
+        let id = gen.next_u128(NodeId(7));
+        assert_eq!((id >> 76) & 0xf, 8, "UUID version");
+        assert_eq!((id >> 62) & 0b11, 0b10, "UUID variant");
+    }
+
+    #[test]
+    fn test_u128_sortable_by_time() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 1000);
+        let gen = IdGenerator::with_clock(
+            ConfigPreset::ShortEpochMaxNodes,
+            DEFAULT_EPOCH,
+            clock.clone(),
+        );
+
+        let mut ids = Vec::new();
+        for _ in 0..5 {
+            for node_id in [9, 3, 5] {
+                ids.push(gen.next_u128(NodeId(node_id)));
+            }
+            clock.advance(1);
+        }
+
+        let mut sorted = ids.clone();
+        sorted.sort_unstable();
+        let times: Vec<u64> = sorted.iter().map(|&id| gen.decode_u128(id).time).collect();
+        assert!(times.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(times.first(), Some(&1000));
+        assert_eq!(times.last(), Some(&1004));
+    }
+
+    #[cfg(feature = "uuid")]
+    #[test]
+    fn test_uuid_conversion() {
+        let gen = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        let id = gen.next_uuid(NodeId(3));
+        assert_eq!(id.get_version_num(), 8);
+        assert_eq!(id.get_variant(), uuid::Variant::RFC4122);
+        assert_eq!(gen.decode_uuid(&id).node_id, 3);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_decode_to_json() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let gen =
+            IdGenerator::with_clock(ConfigPreset::ShardedConfig, DEFAULT_EPOCH, clock.clone());
+        let id = gen.derive_sharded_id(gen.next_id(NodeId(3)), 4);
+
+        let json = gen.decode_to_json(id);
+        let object = json.as_object().unwrap();
+        assert_eq!(object.len(), 6);
+        assert_eq!(json["time"], 5000);
+        assert_eq!(json["node_id"], 3);
+        assert_eq!(json["shard_id"], 4);
+        assert_eq!(json["incrementing_id"], 0);
+        assert_eq!(json["config_id"], 1);
+        assert_eq!(json["timestamp_millis"], DEFAULT_EPOCH + 5000);
+
+        // The core fields match the plain serialization of DecodedId
+        let decoded = serde_json::to_value(gen.decode_id(id)).unwrap();
+        for (key, value) in decoded.as_object().unwrap() {
+            assert_eq!(&object[key], value);
+        }
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_dedup_guard_catches_duplicates() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let guard = DedupGuard::new(IdGenerator::with_clock(
+            ConfigPreset::ShardedConfig,
+            DEFAULT_EPOCH,
+            clock.clone(),
+        ));
+
+        // Exhaust the frozen millisecond's sequence
+        for _ in 0..1024 {
+            guard.next_id(NodeId(7));
+        }
+        assert_eq!(guard.len(), 1024);
+
+        // A second generator on the same node and frozen clock, as after a
+        // restart within the same millisecond, walks the same sequence again
+        let restarted =
+            IdGenerator::with_clock(ConfigPreset::ShardedConfig, DEFAULT_EPOCH, clock.clone());
+        let first = restarted.next_id(NodeId(7));
+        assert_eq!(guard.check(first), Err(DuplicateId(first)));
+
+        clock.advance(1);
+        assert!(guard.check(restarted.next_id(NodeId(7))).is_ok());
+        guard.next_id(NodeId(7));
+        assert_eq!(guard.len(), 1026);
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    #[should_panic(expected = "was issued more than once")]
+    fn test_dedup_guard_panics_on_duplicate() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let gen =
+            IdGenerator::with_clock(ConfigPreset::ShardedConfig, DEFAULT_EPOCH, clock.clone());
+        let guard = DedupGuard::new(IdGenerator::with_clock(
+            ConfigPreset::ShardedConfig,
+            DEFAULT_EPOCH,
+            clock,
+        ));
+        guard.check(gen.next_id(NodeId(1))).unwrap();
+        guard.next_id(NodeId(1));
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn test_chrono_epoch() {
+        use chrono::{DateTime, TimeZone, Utc};
+
+        let epoch = Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 0).unwrap();
+        let gen = IdGenerator::builder()
+            .epoch_datetime(epoch)
+            .epoch_bits(41)
+            .node_bits(10)
+            .config_id(2)
+            .build()
+            .unwrap();
+        assert_eq!(gen.epoch, DEFAULT_EPOCH);
+
+        let before = DateTime::<Utc>::from(SystemTime::now());
+        let issued = gen.decode_id(gen.next_id(NodeId(1))).datetime(epoch);
+        let after = DateTime::<Utc>::from(SystemTime::now());
+        // Ids only carry whole milliseconds
+        assert!(issued >= before - chrono::Duration::milliseconds(1));
+        assert!(issued <= after);
+    }
+
+    #[test]
+    fn test_try_decode_rejects_other_configs() {
+        let sharded = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        let short = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
+
+        let sharded_id = sharded.next_id(NodeId(1));
+        let short_id = short.next_id(NodeId(1));
+
+        assert_eq!(sharded.try_decode_id(sharded_id).unwrap().node_id, 1);
+        assert_eq!(short.try_decode_id(short_id).unwrap().node_id, 1);
+        assert_eq!(
+            short.try_decode_id(sharded_id).unwrap_err(),
+            DecodeError::ConfigMismatch {
+                expected: 3,
+                found: 1
+            }
+        );
+        assert_eq!(
+            sharded.try_decode_id(short_id).unwrap_err(),
+            DecodeError::ConfigMismatch {
+                expected: 1,
+                found: 3
+            }
+        );
+    }
+
+    #[test]
+    fn test_id_stream() {
+        let gen = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
+        let ids: Vec<u64> = gen.iter(NodeId(4)).take(2000).collect();
+        assert_eq!(ids.len(), 2000);
+
+        let unique: HashSet<u64> = ids.iter().copied().collect();
+        assert_eq!(unique.len(), 2000);
+        assert!(ids.iter().all(|&id| gen.decode_id(id).node_id == 4));
+    }
+
+    #[test]
+    fn test_decode_with_matches_decode_id() {
+        let presets = [
+            (ConfigPreset::ShortEpochMaxNodes, (37, 14, 0)),
+            (ConfigPreset::ShardedConfig, (32, 14, 5)),
+            (
+                ConfigPreset::Custom(DEFAULT_EPOCH, 36, 13, 2, 10, 5),
+                (36, 13, 2),
+            ),
+        ];
+        for (preset, (epoch_bits, node_bits, shard_bits)) in presets {
+            let clock = MockClock::new(DEFAULT_EPOCH + 1234);
+            let gen = IdGenerator::with_clock(preset, DEFAULT_EPOCH, clock);
+            let mut id = gen.next_id(NodeId(77));
+            if shard_bits > 0 {
+                id = gen.derive_sharded_id(id, 3);
+            }
+            let decoded = decode_with(id, DEFAULT_EPOCH, epoch_bits, node_bits, shard_bits);
+            assert_eq!(decoded.to_string(), gen.decode_id(id).to_string());
+            assert_eq!(
+                decoded.timestamp_millis(DEFAULT_EPOCH),
+                DEFAULT_EPOCH + 1234
+            );
+        }
+    }
+
+    #[test]
+    fn test_cmp_by_time() {
+        use std::cmp::Ordering::*;
+
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let gen =
+            IdGenerator::with_clock(ConfigPreset::ShardedConfig, DEFAULT_EPOCH, clock.clone());
+
+        let early_high_node = gen.next_id(NodeId(16383));
+        let early_low_node = gen.next_id(NodeId(0));
+        clock.advance(1);
+        let late = gen.next_id(NodeId(0));
+
+        assert_eq!(gen.cmp_by_time(early_high_node, late), Less);
+        assert_eq!(gen.cmp_by_time(late, early_high_node), Greater);
+        // Same millisecond ties regardless of node or sequence
+        assert_eq!(gen.cmp_by_time(early_high_node, early_low_node), Equal);
+        assert!(early_high_node > early_low_node);
+    }
+
+    #[test]
+    fn test_sortable_id_orders_time_major() {
+        use rand::seq::SliceRandom;
+
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let gen =
+            IdGenerator::with_clock(ConfigPreset::ShardedConfig, DEFAULT_EPOCH, clock.clone());
+        let mut ids = Vec::new();
+        for _ in 0..20 {
+            ids.push(gen.next_id(NodeId(16383)));
+            ids.push(gen.derive_sharded_id(gen.next_id(NodeId(0)), 7));
+            ids.push(gen.next_id(NodeId(42)));
+            clock.advance(1);
+        }
+
+        let mut sorted: Vec<_> = ids.iter().map(|&id| SortableId(id, &gen)).collect();
+        sorted.shuffle(&mut rand::thread_rng());
+        sorted.sort();
+
+        let keys: Vec<_> = sorted
+            .iter()
+            .map(|id| {
+                let decoded = gen.decode_id(id.0);
+                (decoded.time, decoded.node_id)
+            })
+            .collect();
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(keys[0], (5000, 0));
+        assert_eq!(keys[59], (5019, 16383));
+        assert_eq!(SortableId(ids[0], &gen), SortableId(ids[0], &gen));
+    }
+
+    #[test]
+    fn test_same_generation_window() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let gen =
+            IdGenerator::with_clock(ConfigPreset::ShardedConfig, DEFAULT_EPOCH, clock.clone());
+
+        let burst = gen.next_ids(NodeId(4), 2);
+        assert!(gen.same_generation_window(burst[0], burst[1]));
+        // Shards don't change the window
+        assert!(gen.same_generation_window(burst[0], gen.derive_sharded_id(burst[1], 9)));
+        assert!(!gen.same_generation_window(burst[0], gen.next_id(NodeId(5))));
+
+        let before = gen.next_id(NodeId(4));
+        clock.advance(1);
+        let after = gen.next_id(NodeId(4));
+        assert!(!gen.same_generation_window(before, after));
+    }
+
+    #[test]
+    fn test_validate_untrusted_ids() {
+        let now = DEFAULT_EPOCH + 5000;
+        let clock = MockClock::new(now);
+        let gen =
+            IdGenerator::with_clock(ConfigPreset::ShardedConfig, DEFAULT_EPOCH, clock.clone());
+        let id = gen.derive_sharded_id(gen.next_id(NodeId(200)), 17);
+        assert_eq!(gen.validate(id, now, 0), Ok(()));
+
+        let other = IdGenerator::with_clock(
+            ConfigPreset::ShortEpochMaxNodes,
+            DEFAULT_EPOCH,
+            clock.clone(),
+        );
+        assert_eq!(
+            gen.validate(other.next_id(NodeId(200)), now, 0),
+            Err(ValidationError::ConfigMismatch {
+                expected: 1,
+                found: 3
+            })
+        );
+
+        // Ahead of now by more than the allowed skew
+        clock.advance(250);
+        let ahead = gen.next_id(NodeId(200));
+        assert_eq!(gen.validate(ahead, now, 250), Ok(()));
+        assert_eq!(
+            gen.validate(ahead, now, 100),
+            Err(ValidationError::TimeInFuture {
+                timestamp_millis: now + 250,
+                limit: now + 100
+            })
+        );
+
+        // A 20-bit node field takes every node id it can hold, but node
+        // fields past 31 bits and shard fields past 16 can hold values no
+        // call to next_id or derive_sharded_id produces
+        let wide_node = IdGenerator::new(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 30, 20, 0, 10, 2),
+            DEFAULT_EPOCH,
+        );
+        assert_eq!(wide_node.validate((70_000 << 13) | 2, now, 0), Ok(()));
+        let widest_node = IdGenerator::new(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 19, 32, 0, 10, 2),
+            DEFAULT_EPOCH,
+        );
+        assert_eq!(
+            widest_node.validate((1 << 31 << 13) | 2, now, 0),
+            Err(ValidationError::NodeOutOfRange {
+                node_id: 1 << 31,
+                max_nodes: 1 << 31
+            })
+        );
+        let wide_shard = IdGenerator::new(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 30, 8, 18, 5, 2),
+            DEFAULT_EPOCH,
+        );
+        assert_eq!(
+            wide_shard.validate((70_000 << 8) | 2, now, 0),
+            Err(ValidationError::ShardOutOfRange {
+                shard: 70_000,
+                max: 65536
+            })
+        );
+
+        // Snowflake ids use 63 bits, leaving the top one clear
+        let snowflake =
+            IdGenerator::with_clock(ConfigPreset::Snowflake, DEFAULT_EPOCH, clock.clone());
+        let valid = snowflake.next_id(NodeId(5));
+        assert_eq!(snowflake.validate(valid, now + 250, 0), Ok(()));
+        assert_eq!(
+            snowflake.validate(valid | 1 << 63, now + 250, 0),
+            Err(ValidationError::UnusedBitsSet)
+        );
+
+        let checked = IdGenerator::builder()
+            .epoch_bits(37)
+            .node_bits(5)
+            .shard_bits(5)
+            .shard_checksum_bits(4)
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+        let id = checked.derive_sharded_id(checked.next_id(NodeId(3)), 9);
+        assert_eq!(checked.validate(id, now + 250, 0), Ok(()));
+        assert_eq!(
+            checked.validate(id ^ 1 << 63, now + 250, 0),
+            Err(ValidationError::ShardChecksumMismatch)
+        );
+    }
+
+    #[test]
+    fn test_debug_shows_the_layout() {
+        let gen = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        gen.next_ids(NodeId(1), 3);
+        let debug = format!("{:?}", gen);
+        assert!(debug.starts_with("IdGenerator {"));
+        for field in [
+            "epoch_bits: 32",
+            "node_bits: 14",
+            "shard_bits: 5",
+            "inc_bits: 10",
+            "shard_shift: 13",
+            "node_shift: 18",
+            "time_shift: 32",
+            "current_sequence: 3",
+        ] {
+            assert!(debug.contains(field), "{} missing from {}", field, debug);
+        }
+    }
+
+    #[test]
+    fn test_clone_starts_a_fresh_counter() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let gen = IdGenerator::builder()
+            .epoch_bits(41)
+            .node_bits(8)
+            .shard_bits(2)
+            .config_id(2)
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+        let first = gen.next_ids(NodeId(1), 5);
+        let copy = gen.clone();
+        assert_eq!(copy.layout(), gen.layout());
+        assert_eq!((gen.current_sequence(), copy.current_sequence()), (5, 0));
+
+        let from_copy = copy.next_ids(NodeId(2), 3);
+        let decoded: Vec<DecodedId> = from_copy.iter().map(|&id| copy.decode_id(id)).collect();
+        assert!(decoded.iter().enumerate().all(|(i, d)| (
+            d.time,
+            d.node_id,
+            d.incrementing_id,
+            d.config_id
+        ) == (5000, 2, i as u64, 2)));
+        assert_eq!(gen.decode_id(first[4]).incrementing_id, 4);
+        assert_eq!(gen.decode_id(gen.next_id(NodeId(1))).incrementing_id, 5);
+        assert_eq!(gen.try_decode_id(from_copy[0]), Ok(decoded[0]));
+
+        // The clock is shared
+        clock.advance(7);
+        assert_eq!(copy.decode_id(copy.next_id(NodeId(2))).time, 5007);
+    }
+
+    #[test]
+    fn test_reserve_sequences_across_a_wrap() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let gen =
+            IdGenerator::with_clock(ConfigPreset::ShardedConfig, DEFAULT_EPOCH, clock.clone());
+        assert_eq!(gen.reserve_sequences(1000), (0, 999));
+        let (start, end) = gen.reserve_sequences(50);
+        assert_eq!((start, end), (1000, 25));
+        let wrapped: HashSet<u16> = (start..1024).chain(0..=end).collect();
+        assert_eq!(wrapped.len(), 50);
+
+        let (start, end) = gen.reserve_sequences(50);
+        assert_eq!((start, end), (26, 75));
+        let following: HashSet<u16> = (start..=end).collect();
+        assert!(wrapped.is_disjoint(&following));
+
+        // Ids carry on from the counter
+        assert_eq!(gen.decode_id(gen.next_id(NodeId(3))).incrementing_id, 76);
+        assert_eq!(gen.current_sequence(), 1101);
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't reserve 0 sequence numbers")]
+    fn test_reserve_sequences_refuses_empty_ranges() {
+        let gen = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        gen.reserve_sequences(0);
+    }
+
+    #[test]
+    fn test_gen_id_conversions() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let gen =
+            IdGenerator::with_clock(ConfigPreset::ShardedConfig, DEFAULT_EPOCH, clock.clone());
+        let first = gen.next_gen_id(NodeId(3));
+        let second = gen.try_next_gen_id(NodeId(3)).unwrap();
+        assert!(first < second);
+        assert_eq!(gen.decode_id(second.into()).incrementing_id, 1);
+
+        let raw: u64 = first.into();
+        assert_eq!(GenId::from(raw), first);
+        assert_eq!(first.to_string(), raw.to_string());
+        assert_eq!(raw.to_string().parse::<GenId>(), Ok(first));
+        assert!("12a".parse::<GenId>().is_err());
+        assert!("-1".parse::<GenId>().is_err());
+        assert_eq!(GenId(u64::MAX).to_string(), "18446744073709551615");
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_gen_id_serde_round_trip() {
+        let id = GenId(0x0123_4567_89ab_cdef);
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(json, 0x0123_4567_89ab_cdef_u64.to_string());
+        assert_eq!(serde_json::from_str::<GenId>(&json).unwrap(), id);
+        assert_eq!(
+            serde_json::from_str::<Vec<GenId>>("[1,2]").unwrap(),
+            [GenId(1), GenId(2)]
+        );
+    }
+
+    #[test]
+    fn test_from_env() {
+        // Parsed through a lookup over a fixed set of variables; setting the
+        // real ones would race with other tests reading the environment
+        let from_vars = |vars: &[(&str, &str)]| {
+            IdGenerator::from_vars(|name| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| value.to_string())
+                    .ok_or(std::env::VarError::NotPresent)
+            })
+        };
+
+        let defaults = from_vars(&[]).unwrap();
+        assert_eq!(
+            defaults.layout(),
+            IdGenerator::builder().build().unwrap().layout()
+        );
+        assert_eq!(defaults.epoch(), DEFAULT_EPOCH);
+
+        let vars = [
+            ("GEN_ID_EPOCH", "1600000000000"),
+            ("GEN_ID_EPOCH_BITS", "36"),
+            ("GEN_ID_NODE_BITS", " 10 "),
+            ("GEN_ID_SHARD_BITS", "5"),
+            ("GEN_ID_CONFIG_ID", "6"),
+        ];
+        let gen = from_vars(&vars).unwrap();
+        assert_eq!(gen.epoch(), 1_600_000_000_000);
+        assert_eq!(
+            (
+                gen.epoch_bits(),
+                gen.node_bits(),
+                gen.shard_bits(),
+                gen.config_id()
+            ),
+            (36, 10, 5, 6)
+        );
+        assert_eq!(gen.decode_id(gen.next_id(NodeId(1023))).node_id, 1023);
+
+        let with_node_bits = |node_bits| {
+            let mut vars = vars;
+            vars[2].1 = node_bits;
+            from_vars(&vars)
+        };
+        for bad in ["lots", "-1", "300", ""] {
+            assert_eq!(
+                with_node_bits(bad).err(),
+                Some(ConfigError::InvalidEnvVar("GEN_ID_NODE_BITS"))
+            );
+        }
+        let not_unicode = IdGenerator::from_vars(|name| match name {
+            "GEN_ID_SHARD_BITS" => Err(std::env::VarError::NotUnicode("\u{fffd}".into())),
+            _ => Err(std::env::VarError::NotPresent),
+        });
+        assert_eq!(
+            not_unicode.err(),
+            Some(ConfigError::InvalidEnvVar("GEN_ID_SHARD_BITS"))
+        );
+
+        // Parsed values are still checked as a layout
+        assert!(matches!(
+            with_node_bits("14"),
+            Err(ConfigError::BitBudgetExceeded { overflow: 4, .. })
+        ));
+        let mut vars = vars;
+        vars[4].1 = "8";
+        assert!(matches!(
+            from_vars(&vars),
+            Err(ConfigError::ConfigIdTooLarge { config_id: 8, .. })
+        ));
+    }
+
+    #[test]
+    fn test_on_exhaustion_fires_once_per_wait() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let exhausted = Arc::new(AtomicU64::new(0));
+        let callback: Box<dyn Fn() + Send + Sync> = {
+            let exhausted = exhausted.clone();
+            let clock = clock.clone();
+            // The clock is frozen, so let the wait end by moving it on
+            Box::new(move || {
+                exhausted.fetch_add(1, Ordering::SeqCst);
+                clock.advance(1);
+            })
+        };
+        let gen = IdGenerator::builder()
+            .epoch_bits(41)
+            .node_bits(10)
+            .config_id(2)
+            .clock(clock.clone())
+            .on_exhaustion(callback)
+            .build()
+            .unwrap();
+
+        gen.next_ids(NodeId(4), 1024);
+        assert_eq!(exhausted.load(Ordering::SeqCst), 0);
+        let id = gen.next_id(NodeId(4));
+        assert_eq!(exhausted.load(Ordering::SeqCst), 1);
+        assert_eq!(gen.decode_id(id).time, 5001);
+
+        gen.next_ids(NodeId(4), 1023 + 1024);
+        assert_eq!(exhausted.load(Ordering::SeqCst), 2);
+        gen.next_id(NodeId(4));
+        assert_eq!(exhausted.load(Ordering::SeqCst), 3);
+        assert_eq!(clock.now_millis(), DEFAULT_EPOCH + 5003);
+    }
+
+    #[test]
+    fn test_on_exhaustion_fires_once_for_concurrent_waiters() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let exhausted = Arc::new(AtomicU64::new(0));
+        let mut gen = IdGenerator::builder()
+            .epoch_bits(41)
+            .node_bits(10)
+            .clock(clock.clone())
+            .on_exhaustion({
+                let exhausted = exhausted.clone();
+                move || {
+                    exhausted.fetch_add(1, Ordering::SeqCst);
+                }
+            })
+            .build()
+            .unwrap();
+        // The clock moves on only once all four threads are waiting
+        let waiting = Arc::new(Mutex::new(HashSet::new()));
+        gen.rollover_wait_hook = Some(Arc::new({
+            let (waiting, clock) = (waiting.clone(), clock.clone());
+            move |_| {
+                let mut waiting = waiting.lock().unwrap();
+                if waiting.insert(thread::current().id()) && waiting.len() == 4 {
+                    clock.advance(1);
+                }
+                drop(waiting);
+                thread::yield_now();
+            }
+        }));
+        let gen = Arc::new(gen);
+        gen.next_ids(NodeId(1), 1024);
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let gen = gen.clone();
+                thread::spawn(move || gen.next_id(NodeId(1)))
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(gen.decode_id(handle.join().unwrap()).time, 5001);
+        }
+        assert_eq!(exhausted.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_reset_sequence_each_ms() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let gen = IdGenerator::builder()
+            .epoch_bits(41)
+            .node_bits(6)
+            .inc_bits(10)
+            .config_id(2)
+            .clock(clock.clone())
+            .reset_sequence_each_ms(true)
+            .build()
+            .unwrap();
+
+        let mut previous = 0;
+        for ms in 0..4 {
+            let ids = gen.next_ids(NodeId(7), 3 + ms);
+            let seqs: Vec<u64> = ids
+                .iter()
+                .map(|&id| gen.decode_id(id).incrementing_id)
+                .collect();
+            let expected: Vec<u64> = (0..3 + ms as u64).collect();
+            assert_eq!(seqs, expected);
+            assert!(ids[0] > previous);
+            previous = *ids.last().unwrap();
+            clock.advance(1);
+        }
+
+        // The default carries the sequence on into the next millisecond
+        let carry_on =
+            IdGenerator::with_clock(ConfigPreset::ShardedConfig, DEFAULT_EPOCH, clock.clone());
+        carry_on.next_ids(NodeId(7), 3);
+        clock.advance(1);
+        assert_eq!(
+            carry_on
+                .decode_id(carry_on.next_id(NodeId(7)))
+                .incrementing_id,
+            3
+        );
+    }
+
+    #[test]
+    fn test_reencode_between_layouts() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let sharded =
+            IdGenerator::with_clock(ConfigPreset::ShardedConfig, DEFAULT_EPOCH, clock.clone());
+        // An earlier epoch, a wider time field and fewer node bits
+        let custom = IdGenerator::new(
+            ConfigPreset::Custom(DEFAULT_EPOCH - 1000, 36, 10, 5, 10, 6),
+            DEFAULT_EPOCH - 1000,
+        );
+
+        sharded.next_ids(NodeId(200), 3);
+        let id = sharded.derive_sharded_id(sharded.next_id(NodeId(200)), 17);
+        let original = sharded.decode_id(id);
+
+        let moved = custom.reencode(id, &sharded).unwrap();
+        let decoded = custom.decode_id(moved);
+        assert_eq!(decoded.time, original.time + 1000);
+        assert_eq!(
+            decoded.timestamp_millis(custom.epoch),
+            original.timestamp_millis(sharded.epoch)
+        );
+        assert_eq!(decoded.node_id, 200);
+        assert_eq!(decoded.shard_id, 17);
+        assert_eq!(decoded.incrementing_id, 3);
+        assert_eq!(decoded.config_id, 6);
+
+        // And back again
+        assert_eq!(sharded.reencode(moved, &custom), Ok(id));
+
+        // Node 16383 needs 14 bits, the custom layout only has 10
+        let wide_node = sharded.next_id(NodeId(16383));
+        assert_eq!(
+            custom.reencode(wide_node, &sharded),
+            Err(ReencodeError::FieldTooWide {
+                field: "node_id",
+                value: 16383,
+                bits: 10
+            })
+        );
+
+        // Times before the target's epoch can't be represented
+        let early = custom.reencode(0, &custom).unwrap();
+        assert_eq!(
+            sharded.reencode(early, &custom),
+            Err(ReencodeError::BeforeEpoch {
+                timestamp_millis: DEFAULT_EPOCH - 1000,
+                epoch: DEFAULT_EPOCH
+            })
+        );
+
+        // A shard has nowhere to go in a layout without shard bits
+        let unsharded = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
+        assert!(matches!(
+            unsharded.reencode(id, &sharded),
+            Err(ReencodeError::FieldTooWide {
+                field: "shard_id",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_field_shifts() {
+        let cases = [
+            (ConfigPreset::ShortEpochMaxNodes, (3, 13, 13, 27)),
+            (ConfigPreset::ShardedConfig, (3, 13, 18, 32)),
+            (ConfigPreset::Snowflake, (0, 12, 12, 22)),
+            (ConfigPreset::ShardedConfigWide, (3, 13, 18, 23)),
+            (
+                ConfigPreset::Custom(DEFAULT_EPOCH, 36, 13, 2, 12, 5),
+                (3, 15, 17, 30),
+            ),
+        ];
+        for (preset, shifts) in cases {
+            let config = IdConfig::new(preset, DEFAULT_EPOCH);
+            assert_eq!(
+                (
+                    config.inc_shift(),
+                    config.shard_shift(),
+                    config.node_shift(),
+                    config.time_shift()
+                ),
+                shifts
+            );
+
+            // The fields really start where the shifts say
+            let id = config.encode(1, 1, 1);
+            assert_eq!(
+                id,
+                (1 << config.time_shift())
+                    | (1 << config.node_shift())
+                    | (1 << config.inc_shift())
+                    | config.config_id as u64
+            );
+        }
+    }
+
+    #[test]
+    fn test_total_bits() {
+        let cases = [
+            // 3 + 10 + 5 + 14 + 32
+            (ConfigPreset::ShardedConfig, 64),
+            // 3 + 10 + 0 + 14 + 37
+            (ConfigPreset::ShortEpochMaxNodes, 64),
+            (ConfigPreset::ShardedConfigWide, 64),
+            // No config bits and 12 sequence bits leave the top bit unused
+            (ConfigPreset::Snowflake, 63),
+            (ConfigPreset::Custom(DEFAULT_EPOCH, 30, 8, 0, 10, 2), 51),
+        ];
+        for (preset, total) in cases {
+            let gen = IdGenerator::new(preset, DEFAULT_EPOCH);
+            assert_eq!(gen.total_bits(), total);
+            assert_eq!(gen.layout().total_bits(), total);
+            assert_eq!(IdConfig::from(&gen).total_bits(), total);
+        }
+
+        let checked = IdGenerator::builder()
+            .epoch_bits(37)
+            .node_bits(5)
+            .shard_bits(5)
+            .shard_checksum_bits(4)
+            .build()
+            .unwrap();
+        assert_eq!(checked.total_bits(), 64);
+    }
+
+    #[test]
+    fn test_layout_breakdown() {
+        let gen = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        let layout = gen.layout();
+        let fields: Vec<(&str, u8, u8)> = layout
+            .fields
+            .iter()
+            .map(|field| (field.name, field.offset, field.width))
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                ("config", 0, 3),
+                ("inc", 3, 10),
+                ("shard", 13, 5),
+                ("node", 18, 14),
+                ("time", 32, 32),
+                ("shard_checksum", 64, 0),
+            ]
+        );
+        assert_eq!(layout.total_bits(), 64);
+        assert_eq!(
+            layout.to_string(),
+            "config@0:3 inc@3:10 shard@13:5 node@18:14 time@32:32 shard_checksum@64:0"
+        );
+
+        // Each field starts where the one below it ends
+        for preset in [
+            ConfigPreset::ShortEpochMaxNodes,
+            ConfigPreset::Snowflake,
+            ConfigPreset::Custom(DEFAULT_EPOCH, 36, 13, 2, 12, 5),
+        ] {
+            let layout = IdGenerator::new(preset, DEFAULT_EPOCH).layout();
+            for pair in layout.fields.windows(2) {
+                assert_eq!(pair[1].offset, pair[0].offset + pair[0].width);
+            }
+        }
+        let snowflake = IdGenerator::new(ConfigPreset::Snowflake, 0).layout();
+        assert_eq!(snowflake.total_bits(), 63);
+    }
+
+    #[test]
+    fn test_core_encode_matches_generator() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let gen =
+            IdGenerator::with_clock(ConfigPreset::ShardedConfig, DEFAULT_EPOCH, clock.clone());
+        let config = IdConfig::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        assert_eq!(config, IdConfig::from(&gen));
+
+        let id = gen.next_id(NodeId(42));
+        assert_eq!(config.encode_at(DEFAULT_EPOCH + 5000, 42, 0), Ok(id));
+        assert_eq!(config.decode(id).node_id, 42);
+        assert_eq!(
+            config.derive_sharded_id(id, 7),
+            gen.try_derive_sharded_id(id, 7)
+        );
+
+        assert_eq!(
+            config.encode_at(DEFAULT_EPOCH - 1, 42, 0),
+            Err(GenError::ClockBehindEpoch {
+                epoch: DEFAULT_EPOCH,
+                now: DEFAULT_EPOCH - 1
+            })
+        );
+        assert!(matches!(
+            config.encode_at(DEFAULT_EPOCH, 16384, 0),
+            Err(GenError::NodeIdOutOfRange { .. })
+        ));
+    }
+
+    #[test]
+    fn test_initial_sequence() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let mut gen =
+            IdGenerator::with_initial_sequence(ConfigPreset::ShardedConfig, DEFAULT_EPOCH, 700);
+        gen.clock = Arc::new(clock.clone());
+        assert_eq!(gen.current_sequence(), 700);
+
+        let first = gen.decode_id(gen.next_id(NodeId(1)));
+        assert_eq!(first.incrementing_id, 700);
+        assert_eq!(gen.current_sequence(), 701);
+
+        // A restored generator picks up where the saved one left off
+        for _ in 0..10 {
+            gen.next_id(NodeId(1));
+        }
+        let saved = gen.current_sequence();
+        let mut resumed =
+            IdGenerator::with_initial_sequence(ConfigPreset::ShardedConfig, DEFAULT_EPOCH, saved);
+        resumed.clock = Arc::new(clock.clone());
+        clock.advance(1);
+        assert_eq!(
+            resumed
+                .decode_id(resumed.next_id(NodeId(1)))
+                .incrementing_id,
+            711
+        );
+
+        // Counter values past the 10-bit field wrap like the running counter
+        let gen =
+            IdGenerator::with_initial_sequence(ConfigPreset::ShardedConfig, DEFAULT_EPOCH, 1030);
+        assert_eq!(
+            gen.decode_id(gen.next_id(NodeId(1))).incrementing_id,
+            1030 & 1023
+        );
+        assert_eq!(gen.current_sequence(), 1031);
+    }
+
+    #[test]
+    fn test_remaining_in_window() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let gen =
+            IdGenerator::with_clock(ConfigPreset::ShardedConfig, DEFAULT_EPOCH, clock.clone());
+        assert_eq!(gen.remaining_in_window(), 1024);
+
+        for issued in 1..=500 {
+            gen.next_id(NodeId(1));
+            assert_eq!(gen.remaining_in_window(), 1024 - issued);
+        }
+        gen.next_ids(NodeId(2), 524);
+        assert_eq!(gen.remaining_in_window(), 0);
+
+        clock.advance(1);
+        assert_eq!(gen.remaining_in_window(), 1024);
+        gen.next_id(NodeId(1));
+        assert_eq!(gen.remaining_in_window(), 1023);
+    }
+
+    #[test]
+    fn test_last_timestamp_tracks_clock() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let gen = IdGenerator::builder()
+            .epoch_bits(41)
+            .node_bits(10)
+            .clock(clock.clone())
+            .monotonic(10)
+            .build()
+            .unwrap();
+        assert_eq!(gen.last_timestamp(), DEFAULT_EPOCH);
+
+        gen.next_id(NodeId(1));
+        assert_eq!(gen.last_timestamp(), DEFAULT_EPOCH + 5000);
+        clock.advance(7);
+        assert_eq!(gen.last_timestamp(), DEFAULT_EPOCH + 5000);
+        gen.next_id(NodeId(1));
+        assert_eq!(gen.last_timestamp(), DEFAULT_EPOCH + 5007);
+
+        // A regression within the tolerance keeps the last millisecond
+        clock.set(DEFAULT_EPOCH + 5003);
+        gen.next_id(NodeId(1));
+        assert_eq!(gen.last_timestamp(), DEFAULT_EPOCH + 5007);
+
+        let per_node =
+            IdGenerator::with_per_node_sequences(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        let before = SystemClock.now_millis();
+        per_node.next_id(NodeId(3));
+        assert!(per_node.last_timestamp() >= before);
+    }
+
+    #[test]
+    fn test_time_travel_resilience() {
+        let gen = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, u64::MAX);
+        match gen.try_next_id(NodeId(1)) {
+            Err(GenError::ClockBehindEpoch { epoch, now }) => {
+                assert_eq!(epoch, u64::MAX);
+                assert!(now < epoch);
+            }
+            other => panic!("expected ClockBehindEpoch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_try_new_rejects_future_epoch() {
+        match IdGenerator::try_new(ConfigPreset::ShortEpochMaxNodes, u64::MAX) {
+            Err(ConfigError::EpochInFuture { epoch, now }) => {
+                assert_eq!(epoch, u64::MAX);
+                assert!(now < epoch);
+            }
+            other => panic!("expected EpochInFuture, got {:?}", other.map(|_| ())),
+        }
+        let tomorrow = SystemClock.now_millis() + 86_400_000;
+        assert!(matches!(
+            IdGenerator::try_new(ConfigPreset::ShardedConfig, tomorrow),
+            Err(ConfigError::EpochInFuture { epoch, .. }) if epoch == tomorrow
+        ));
+        // The layout is checked first
+        assert!(matches!(
+            IdGenerator::try_new(ConfigPreset::Custom(tomorrow, 51, 1, 0, 10, 2), tomorrow),
+            Err(ConfigError::BitBudgetExceeded { .. })
+        ));
+
+        // A Custom preset's own epoch is the one checked, not the argument
+        assert!(matches!(
+            IdGenerator::try_new(
+                ConfigPreset::Custom(u64::MAX / 2, 41, 10, 0, 10, 1),
+                DEFAULT_EPOCH
+            ),
+            Err(ConfigError::EpochInFuture { epoch, .. }) if epoch == u64::MAX / 2
+        ));
+        let custom = IdGenerator::try_new(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 41, 10, 0, 10, 1),
+            u64::MAX / 2,
+        )
+        .unwrap();
+        assert_eq!(custom.epoch(), DEFAULT_EPOCH);
+        assert!(custom.try_next_id(NodeId(1)).is_ok());
+
+        // The trusted constructor leaves the failure to the first id
+        let trusted = IdGenerator::try_new_trusted(ConfigPreset::ShardedConfig, tomorrow).unwrap();
+        assert!(matches!(
+            trusted.try_next_id(NodeId(1)),
+            Err(GenError::ClockBehindEpoch { .. })
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "Time went backwards")]
+    fn test_next_id_panics_on_clock_behind_epoch() {
+        let gen = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, u64::MAX);
+        let _ = gen.next_id(NodeId(1));
+    }
+
+    #[test]
+    fn test_node_id_encoding() {
+        let gen = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
+        let max_nodes = gen.max_nodes;
+        let test_node_ids = [0, 1, 2, 3, 4, 5, 6, max_nodes / 2, max_nodes - 1];
+
+        for &node_id in &test_node_ids {
+            let id = gen.next_id(NodeId(node_id));
             let decoded = gen.decode_id(id);
             assert_eq!(
                 decoded.node_id, node_id as u64,
@@ -310,11 +6760,1189 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_fields_past_bit_63_decode_as_zero() {
+        // 3 + 15 + 20 + 30 = 68, so the time field starts past the word
+        let config = IdConfig::new(ConfigPreset::Custom(DEFAULT_EPOCH, 10, 30, 20, 15, 2), 0);
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::BitBudgetExceeded { overflow: 14, .. })
+        ));
+        assert!(
+            IdGenerator::try_new(ConfigPreset::Custom(DEFAULT_EPOCH, 10, 30, 20, 15, 2), 0)
+                .is_err()
+        );
+
+        assert_eq!(config.time_shift(), 68);
+        let decoded = config.decode(u64::MAX);
+        assert_eq!(decoded.time, 0);
+        assert_eq!(decoded.node_id, (1 << 26) - 1);
+        assert_eq!(decoded.shard_id, ((1u32 << 20) - 1) as u16);
+        assert_eq!(config.encode(123, 0, 0), 2);
+
+        // Widths summing past 255 saturate instead of overflowing
+        let huge = IdConfig {
+            epoch_bits: 200,
+            node_bits: 250,
+            ..config
+        };
+        assert_eq!(huge.time_shift(), u8::MAX);
+        assert_eq!(huge.decode(u64::MAX).time, 0);
+        assert!(huge.validate().is_err());
+    }
+
+    #[test]
+    fn test_layout_constants_match_encoding() {
+        assert_eq!(IdGenerator::CONFIG_BITS, 3);
+        assert_eq!(IdGenerator::INC_BITS, 10);
+
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let gen = IdGenerator::with_clock(ConfigPreset::ShardedConfig, DEFAULT_EPOCH, clock);
+        gen.next_ids(NodeId(1), 5);
+        let id = gen.next_id(NodeId(0));
+        assert_eq!(
+            id & ((1 << IdGenerator::CONFIG_BITS) - 1),
+            gen.config_id as u64
+        );
+        assert_eq!(
+            (id >> IdGenerator::CONFIG_BITS) & ((1 << IdGenerator::INC_BITS) - 1),
+            5
+        );
+        assert_eq!(
+            IdConfig::from(&gen).shard_shift(),
+            IdGenerator::CONFIG_BITS + IdGenerator::INC_BITS
+        );
+        let built = IdGenerator::builder().build().unwrap();
+        assert_eq!(
+            (built.config_bits, built.inc_bits),
+            (IdGenerator::CONFIG_BITS, IdGenerator::INC_BITS)
+        );
+    }
+
+    #[cfg(feature = "secure")]
+    #[test]
+    fn test_next_id_random() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let gen =
+            IdGenerator::with_clock(ConfigPreset::ShardedConfig, DEFAULT_EPOCH, clock.clone());
+        let mut ids = Vec::new();
+        for _ in 0..4 {
+            ids.extend((0..200).map(|_| gen.next_id_random(NodeId(9))));
+            clock.advance(1);
+        }
+
+        let decoded: Vec<DecodedId> = ids.iter().map(|&id| gen.decode_id(id)).collect();
+        assert!(decoded.windows(2).all(|w| w[0].time <= w[1].time));
+        assert!(decoded
+            .windows(2)
+            .all(|w| w[0].time < w[1].time || w[0].incrementing_id != w[1].incrementing_id));
+        assert_eq!(ids.iter().collect::<HashSet<_>>().len(), ids.len());
+        // A counter would have issued 0..200 in the first millisecond
+        assert!(decoded[..200]
+            .iter()
+            .enumerate()
+            .any(|(i, d)| d.incrementing_id != i as u64));
+        assert!(decoded.iter().all(|d| d.node_id == 9));
+        assert!(gen.try_next_id_random(wide_node(16384)).is_err());
+
+        // With 4 sequence values a millisecond, draws past the 4th wait for
+        // the clock instead of reusing one
+        let narrow = IdGenerator::new(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 41, 10, 0, 2, 2),
+            DEFAULT_EPOCH,
+        );
+        let ids: Vec<u64> = (0..40).map(|_| narrow.next_id_random(NodeId(1))).collect();
+        assert_eq!(ids.iter().collect::<HashSet<_>>().len(), 40);
+        let mut per_ms = std::collections::HashMap::new();
+        for id in &ids {
+            *per_ms.entry(narrow.decode_id(*id).time).or_insert(0) += 1;
+        }
+        assert!(per_ms.values().all(|&n| n <= 4));
+        assert!(ids
+            .windows(2)
+            .all(|w| narrow.decode_id(w[0]).time <= narrow.decode_id(w[1]).time));
+    }
+
+    #[cfg(feature = "cluster")]
+    #[test]
+    fn test_node_id_allocator() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let store = Arc::new(MemoryNodeStore::new());
+        let allocator = || NodeIdAllocator::with_clock(store.clone(), 1000, clock.clone());
+        let preset = || ConfigPreset::Custom(DEFAULT_EPOCH, 41, 3, 0, 10, 2);
+
+        // Each allocator stands in for a separate process
+        let allocators: Vec<NodeIdAllocator> = (0..9).map(|_| allocator()).collect();
+        let mut leases = Vec::new();
+        let mut nodes = HashSet::new();
+        for allocator in &allocators[..8] {
+            let (gen, lease) =
+                IdGenerator::new_with_allocator(preset(), DEFAULT_EPOCH, allocator).unwrap();
+            assert_eq!(gen.decode_id(gen.next()).node_id, lease.node_id as u64);
+            assert_eq!(lease.expires_at, DEFAULT_EPOCH + 6000);
+            assert!(nodes.insert(lease.node_id));
+            leases.push(lease);
+        }
+        assert_eq!(nodes, (0..8).collect());
+        assert_eq!(
+            IdGenerator::new_with_allocator(preset(), DEFAULT_EPOCH, &allocators[8]).err(),
+            Some(AllocError::PoolExhausted { max_nodes: 8 })
+        );
+
+        // A released node id goes to the next allocation
+        allocators[3].release(leases[3]);
+        assert_eq!(allocators[8].allocate(8).unwrap().node_id, 3);
+
+        // Renewed leases outlive ones left to expire, which others can take
+        clock.advance(600);
+        let renewed = allocators[0].renew(&leases[0]).unwrap();
+        assert_eq!(renewed.expires_at, DEFAULT_EPOCH + 6600);
+        clock.advance(600);
+        let other = allocator();
+        assert_eq!(other.allocate(8).unwrap().node_id, 1);
+        assert_eq!(
+            allocators[1].renew(&leases[1]),
+            Err(AllocError::LeaseLost { node_id: 1 })
+        );
+        assert!(allocators[0].renew(&renewed).is_ok());
+        // Only the holder can release a lease
+        allocators[1].release(leases[1]);
+        assert_eq!(other.allocate(8).unwrap().node_id, 2);
+    }
+
+    #[cfg(feature = "salt")]
+    #[test]
+    fn test_salted_generators_reusing_a_node_id() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let build = || {
+            IdGenerator::builder()
+                .epoch_bits(41)
+                .node_bits(10)
+                .salt_bits(4)
+                .clock(clock.clone())
+                .build()
+                .unwrap()
+        };
+        let a = build();
+        let mut b = build();
+        // Salts are random, so make sure these two differ
+        b.salt = a.salt ^ 1;
+        assert_eq!(a.max_nodes(), 64);
+        assert!(a.salt() < 16);
+
+        let from_a: HashSet<u64> = a.next_ids(NodeId(17), 1024).into_iter().collect();
+        let from_b = b.next_ids(NodeId(17), 1024);
+        assert!(from_b.iter().all(|id| !from_a.contains(id)));
+        for (gen, id) in [(&a, *from_a.iter().next().unwrap()), (&b, from_b[0])] {
+            let decoded = gen.decode_id(id);
+            assert_eq!((decoded.time, decoded.node_id), (5000, 17));
+            assert_eq!(gen.salt_of(id), gen.salt());
+            assert_eq!(gen.node_id_of(id), 17);
+            assert_eq!(gen.encode(&decoded), Ok(id));
+        }
+        assert_eq!(a.self_test(), Ok(()));
+        assert!(a.try_next_id(wide_node(64)).is_err());
+
+        // The salt width travels with the layout, so a persisted config still
+        // reads the salt apart from the node id
+        let id = from_b[0];
+        let json = serde_json::to_string(&IdConfig::from(&b)).unwrap();
+        let config: IdConfig = serde_json::from_str(&json).unwrap();
+        assert_eq!(config.salt_bits, 4);
+        assert_eq!(config.decode(id).node_id, 17);
+        let restored = IdGenerator::from_config(config).unwrap();
+        assert_eq!(restored.decode_id(id).node_id, 17);
+        assert_eq!(restored.max_nodes(), 64);
+
+        let plain = IdGenerator::builder()
+            .epoch_bits(41)
+            .node_bits(10)
+            .build()
+            .unwrap();
+        assert!(!serde_json::to_string(&IdConfig::from(&plain))
+            .unwrap()
+            .contains("salt_bits"));
+        let unsalted = plain.reencode(id, &b).unwrap();
+        assert_eq!(plain.decode_id(unsalted).node_id, 17);
+        let resalted = a.reencode(unsalted, &plain).unwrap();
+        assert_eq!(a.decode_id(resalted).node_id, 17);
+        assert_eq!(a.salt_of(resalted), a.salt());
+
+        assert_eq!(
+            IdGenerator::builder()
+                .node_bits(10)
+                .salt_bits(11)
+                .build()
+                .err(),
+            Some(ConfigError::SaltTooWide {
+                salt_bits: 11,
+                node_bits: 10
+            })
+        );
+    }
+
+    #[test]
+    fn test_decode_id_with_shard_bits_override() {
+        let sharded = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        let short = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
+        let id = sharded.derive_sharded_id(sharded.next_id(NodeId(9000)), 21);
+        let expected = sharded.decode_id(id);
+
+        // Without the override the shard lands in the node field
+        assert_ne!(short.decode_id(id).node_id, 9000);
+
+        let decoded = short.decode_id_with_shard_bits(id, 5);
+        assert_eq!(decoded.node_id, 9000);
+        assert_eq!(decoded.shard_id, 21);
+        assert_eq!(decoded.time, expected.time);
+        assert_eq!(decoded.incrementing_id, expected.incrementing_id);
+        assert_eq!(decoded.config_id, 1);
+
+        assert_eq!(
+            sharded.decode_id_with_shard_bits(id, sharded.shard_bits()),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_deterministic_generators_agree() {
+        let preset = || ConfigPreset::Custom(DEFAULT_EPOCH, 41, 10, 0, 10, 2);
+        let fixed_millis = DEFAULT_EPOCH + 5_000;
+        let a = IdGenerator::deterministic(preset(), DEFAULT_EPOCH, fixed_millis, 100);
+        let b = IdGenerator::deterministic(preset(), DEFAULT_EPOCH, fixed_millis, 100);
+
+        // Neither a clone issuing ids nor a look at the window moves the
+        // other's time along
+        a.clone().next_ids(NodeId(0), 2000);
+        let ids: Vec<u64> = (0..3000).map(|i| a.next_id(NodeId(i % 5))).collect();
+        let again: Vec<u64> = (0..3000)
+            .map(|i| {
+                b.remaining_in_window();
+                b.exhaustion_ratio();
+                b.last_timestamp();
+                b.next_id(NodeId(i % 5))
+            })
+            .collect();
+        assert_eq!(ids, again);
+
+        let first = a.decode_id(ids[0]);
+        assert_eq!(first.time, 5_000);
+        assert_eq!(first.incrementing_id, 100);
+        // The 1025th id is the first past the millisecond's sequence
+        assert_eq!(a.decode_id(ids[1023]).time, 5_000);
+        assert_eq!(a.decode_id(ids[1024]).time, 5_001);
+        assert_eq!(ids.iter().collect::<HashSet<_>>().len(), ids.len());
+
+        let other = IdGenerator::deterministic(preset(), DEFAULT_EPOCH, fixed_millis, 101);
+        assert_ne!(other.next_id(NodeId(0)), ids[0]);
+    }
+
+    #[test]
+    fn test_node_id_of_matches_decode_id() {
+        let presets = [
+            ConfigPreset::ShortEpochMaxNodes,
+            ConfigPreset::ShardedConfig,
+            ConfigPreset::ShardedConfigWide,
+            ConfigPreset::Snowflake,
+            ConfigPreset::Custom(DEFAULT_EPOCH, 41, 10, 0, 10, 2),
+        ];
+        for preset in presets {
+            let gen = IdGenerator::new(preset, DEFAULT_EPOCH);
+            let step = (gen.max_nodes() / 97).max(1);
+            for node_id in (0..gen.max_nodes()).step_by(step as usize) {
+                let mut id = gen.next_id(NodeId(node_id));
+                if gen.supports_sharding() {
+                    id = gen.derive_sharded_id(id, 3);
+                }
+                assert_eq!(gen.node_id_of(id), node_id as u64);
+                assert_eq!(gen.node_id_of(id), gen.decode_id(id).node_id);
+            }
+        }
+    }
+
+    #[test]
+    fn test_guarded_next_id_near_epoch_exhaustion() {
+        // 20 time bits run out about 17 minutes after the epoch
+        let max = (1u64 << 20) - 1;
+        let clock = MockClock::new(DEFAULT_EPOCH + max / 2);
+        let gen = IdGenerator::with_clock(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 20, 10, 0, 10, 2),
+            DEFAULT_EPOCH,
+            clock.clone(),
+        );
+        assert!((gen.exhaustion_ratio() - 0.5).abs() < 1e-6);
+        assert!(gen.try_next_id_guarded(NodeId(1)).is_ok());
+
+        clock.set(DEFAULT_EPOCH + max - 1000);
+        assert!(gen.exhaustion_ratio() > 0.99);
+        assert_eq!(
+            gen.try_next_id_guarded(NodeId(1)),
+            Err(GenError::EpochExhausted {
+                elapsed: max - 1000,
+                max
+            })
+        );
+        // The unguarded path keeps issuing until the field wraps
+        assert!(gen.try_next_id(NodeId(1)).is_ok());
+
+        let strict = IdGenerator::builder()
+            .epoch_bits(20)
+            .node_bits(10)
+            .clock(clock.clone())
+            .exhaustion_threshold(0.25)
+            .build()
+            .unwrap();
+        clock.set(DEFAULT_EPOCH + max / 3);
+        assert!(matches!(
+            strict.try_next_id_guarded(NodeId(1)),
+            Err(GenError::EpochExhausted { .. })
+        ));
+        clock.set(DEFAULT_EPOCH + max / 5);
+        assert!(strict.try_next_id_guarded(NodeId(1)).is_ok());
+
+        clock.set(DEFAULT_EPOCH - 1);
+        assert_eq!(strict.exhaustion_ratio(), 0.0);
+    }
+
+    #[test]
+    fn test_encode_inverts_decode_id() {
+        let sharded = IdGenerator::builder()
+            .epoch_bits(28)
+            .node_bits(14)
+            .shard_bits(5)
+            .config_id(1)
+            .shard_checksum_bits(4)
+            .build()
+            .unwrap();
+        let presets = [
+            IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH),
+            IdGenerator::new(ConfigPreset::Snowflake, DEFAULT_EPOCH),
+            IdGenerator::new(
+                ConfigPreset::Custom(DEFAULT_EPOCH, 41, 10, 0, 10, 2),
+                DEFAULT_EPOCH,
+            ),
+            sharded,
+        ];
+        for gen in presets {
+            for node_id in [0, 1, 17, 1023] {
+                let mut id = gen.next_id(NodeId(node_id));
+                if gen.supports_sharding() {
+                    id = gen.derive_sharded_id(id, 30);
+                }
+                assert_eq!(gen.encode(&gen.decode_id(id)), Ok(id));
+            }
+        }
+
+        let gen = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        let decoded = gen.decode_id(gen.next_id(NodeId(1)));
+        let other_config = DecodedId {
+            config_id: 5,
+            ..decoded
+        };
+        assert_eq!(config_id_of(gen.encode(&other_config).unwrap()), 5);
+        for (field, bad, bits) in [
+            (
+                "config_id",
+                DecodedId {
+                    config_id: 8,
+                    ..decoded
+                },
+                3,
+            ),
+            (
+                "node_id",
+                DecodedId {
+                    node_id: 1 << 14,
+                    ..decoded
+                },
+                14,
+            ),
+            (
+                "shard_id",
+                DecodedId {
+                    shard_id: 32,
+                    ..decoded
+                },
+                5,
+            ),
+            (
+                "time",
+                DecodedId {
+                    time: 1 << 32,
+                    ..decoded
+                },
+                32,
+            ),
+        ] {
+            assert!(matches!(
+                gen.encode(&bad),
+                Err(ReencodeError::FieldTooWide { field: f, bits: b, .. }) if f == field && b == bits
+            ));
+        }
+    }
+
+    #[test]
+    fn test_two_level_shards() {
+        let gen = IdGenerator::builder()
+            .epoch_bits(30)
+            .node_bits(12)
+            .shard1_bits(3)
+            .shard2_bits(6)
+            .build()
+            .unwrap();
+        assert_eq!(gen.shard_bits(), 9);
+
+        let original = gen.next_id(NodeId(700));
+        let expected = gen.decode_id(original);
+        for (region, partition) in [(0, 0), (7, 0), (0, 63), (5, 42), (7, 63)] {
+            let id = gen
+                .derive_two_level_shard(original, region, partition)
+                .unwrap();
+            assert_eq!(gen.shard_levels(id), (region, partition));
+
+            let decoded = gen.decode_id(id);
+            assert_eq!(decoded.shard_id, region << 6 | partition);
+            assert_eq!(decoded.time, expected.time);
+            assert_eq!(decoded.node_id, 700);
+            assert_eq!(decoded.incrementing_id, expected.incrementing_id);
+
+            // Moving one level leaves the other alone
+            let moved = gen
+                .derive_two_level_shard(id, region, 63 - partition)
+                .unwrap();
+            assert_eq!(gen.shard_levels(moved), (region, 63 - partition));
+        }
+
+        assert_eq!(
+            gen.derive_two_level_shard(original, 8, 0),
+            Err(ShardError::ShardOutOfRange { shard: 8, max: 8 })
+        );
+        assert_eq!(
+            gen.derive_two_level_shard(original, 0, 64),
+            Err(ShardError::ShardOutOfRange { shard: 64, max: 64 })
+        );
+
+        let single = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        let id = single.derive_sharded_id(single.next_id(NodeId(1)), 19);
+        assert_eq!(single.shard_levels(id), (19, 0));
+        assert_eq!(
+            single.derive_two_level_shard(id, 1, 1),
+            Err(ShardError::ShardingUnsupported)
+        );
+
+        // 3 + 10 + 9 + 12 + 31 = 65
+        assert!(matches!(
+            IdGenerator::builder()
+                .epoch_bits(31)
+                .node_bits(12)
+                .shard1_bits(3)
+                .shard2_bits(6)
+                .build(),
+            Err(ConfigError::BitBudgetExceeded {
+                shard_bits: 9,
+                overflow: 1,
+                ..
+            })
+        ));
+        assert_eq!(
+            IdGenerator::builder()
+                .shard_bits(2)
+                .shard2_bits(3)
+                .build()
+                .err(),
+            Some(ConfigError::SecondaryShardTooWide {
+                shard2_bits: 3,
+                shard_bits: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_local_generator_single_thread() {
+        let preset = || ConfigPreset::Custom(DEFAULT_EPOCH, 41, 10, 0, 10, 2);
+        let local = LocalIdGenerator::new(preset(), DEFAULT_EPOCH);
+        let ids: Vec<u64> = (0..10_000).map(|i| local.next_id(NodeId(i % 4))).collect();
+        assert_eq!(ids.iter().collect::<HashSet<_>>().len(), ids.len());
+        assert_eq!(local.decode_id(ids[5]).node_id, 1);
+
+        let clock = MockClock::new(DEFAULT_EPOCH + 1000);
+        let local = LocalIdGenerator::with_clock(preset(), DEFAULT_EPOCH, clock.clone());
+        let shared = IdGenerator::with_clock(preset(), DEFAULT_EPOCH, clock.clone());
+        assert_eq!(local.config(), IdConfig::from(&shared));
+        for i in 0..2000u32 {
+            if i % 1000 == 0 {
+                clock.advance(1);
+            }
+            assert_eq!(local.next_id(NodeId(i % 8)), shared.next_id(NodeId(i % 8)));
+        }
+        assert!(local.try_next_id(wide_node(1024)).is_err());
+
+        // Resolves to the blanket impl only when there is no Sync one to
+        // make the call ambiguous
+        trait AmbiguousIfSync<A> {
+            fn check() {}
+        }
+        impl<T: ?Sized> AmbiguousIfSync<()> for T {}
+        impl<T: ?Sized + Sync> AmbiguousIfSync<u8> for T {}
+        <LocalIdGenerator as AmbiguousIfSync<_>>::check();
+    }
+
+    #[test]
+    fn test_atomic_generator() {
+        let preset = || ConfigPreset::Custom(DEFAULT_EPOCH, 41, 10, 0, 10, 2);
+        let clock = MockClock::new(DEFAULT_EPOCH + 1000);
+        let atomic = AtomicIdGenerator::with_clock(preset(), DEFAULT_EPOCH, clock.clone());
+        let locked = IdGenerator::builder()
+            .epoch_bits(41)
+            .node_bits(10)
+            .config_id(2)
+            .reset_sequence_each_ms(true)
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+        assert_eq!(atomic.config(), IdConfig::from(&locked));
+        for i in 0..2000u32 {
+            if i % 700 == 0 {
+                clock.advance(1);
+            }
+            assert_eq!(atomic.next_id(NodeId(i % 8)), locked.next_id(NodeId(i % 8)));
+        }
+        assert!(atomic.try_next_id(wide_node(1024)).is_err());
+        clock.set(DEFAULT_EPOCH + 1002);
+        assert_eq!(
+            atomic.try_next_id(NodeId(1)),
+            Err(GenError::ClockWentBackwards)
+        );
+
+        for ordering in [Ordering::Relaxed, Ordering::SeqCst, Ordering::Release] {
+            let atomic =
+                Arc::new(AtomicIdGenerator::new(preset(), DEFAULT_EPOCH).with_ordering(ordering));
+            let handles: Vec<_> = (0..8)
+                .map(|_| {
+                    let atomic = atomic.clone();
+                    thread::spawn(move || {
+                        (0..5000)
+                            .map(|_| atomic.next_id(NodeId(1)))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            let mut unique = HashSet::new();
+            for handle in handles {
+                for id in handle.join().unwrap() {
+                    assert!(unique.insert(id), "{:?} issued {} twice", ordering, id);
+                }
+            }
+            assert_eq!(unique.len(), 40_000);
+        }
+    }
+
+    #[test]
+    fn test_raw_byte_order_round_trip() {
+        let gen = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        let id = gen.derive_sharded_id(gen.next_id(NodeId(4321)), 27);
+
+        let be = id_to_be_bytes(id);
+        assert_eq!(be[7] & 0b111, 1, "config id is in the last big-endian byte");
+        assert_eq!(id_from_be_bytes(be), id);
+        assert_eq!(gen.decode_id(id_from_be_bytes(be)), gen.decode_id(id));
+
+        let le = id_to_le_bytes(id);
+        assert_eq!(le[0] & 0b111, 1);
+        assert_eq!(id_from_le_bytes(le), id);
+        assert_eq!(gen.decode_id(id_from_le_bytes(le)).shard_id, 27);
+
+        // Reading with the wrong order gives a different id
+        assert_ne!(id_from_le_bytes(be), id);
+
+        let mut ids: Vec<u64> = (0..200).map(|i| gen.next_id(NodeId(i))).collect();
+        ids.extend([0, 255, 256, u64::MAX]);
+        let mut by_bytes = ids.clone();
+        by_bytes.sort_by_key(|&id| id_to_be_bytes(id));
+        ids.sort_unstable();
+        assert_eq!(by_bytes, ids);
+    }
+
+    #[test]
+    fn test_reserve_block_is_contiguous() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 1000);
+        let gen = IdGenerator::with_clock(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 41, 10, 0, 10, 2),
+            DEFAULT_EPOCH,
+            clock.clone(),
+        );
+        let before: Vec<u64> = (0..100).map(|_| gen.next_id(NodeId(3))).collect();
+
+        let block = gen.reserve_block(NodeId(3), 500).unwrap();
+        assert_eq!(block.timestamp_millis, DEFAULT_EPOCH + 1000);
+        assert_eq!((block.node_id.get(), block.count), (3, 500));
+        let ids: Vec<u64> = block.ids().collect();
+        assert_eq!(ids.len(), 500);
+        assert_eq!((ids[0], ids[499]), (block.start_id, block.end_id));
+        for (i, id) in ids.iter().enumerate() {
+            let decoded = gen.decode_id(*id);
+            assert_eq!(decoded.time, 1000);
+            assert_eq!(decoded.node_id, 3);
+            assert_eq!(decoded.incrementing_id, 100 + i as u64);
+            assert!(!before.contains(id));
+        }
+        assert!(ids.windows(2).all(|w| w[1] - w[0] == 1 << 3));
+
+        assert_eq!(
+            gen.reserve_block(NodeId(3), 500),
+            Err(GenError::BlockUnavailable {
+                requested: 500,
+                remaining: 424
+            })
+        );
+        assert_eq!(gen.reserve_block(NodeId(3), 400).unwrap().count, 400);
+
+        // Ids already issued in a millisecond leave the unwrapped part of
+        // the sequence field short
+        clock.advance(1);
+        for _ in 0..10 {
+            gen.next_id(NodeId(3));
+        }
+        assert_eq!(
+            gen.reserve_block(NodeId(3), 1024),
+            Err(GenError::BlockUnavailable {
+                requested: 1024,
+                remaining: 14
+            })
+        );
+
+        // A fresh millisecond has all of it
+        clock.advance(1);
+        let full = gen.reserve_block(NodeId(3), 1024).unwrap();
+        assert_eq!(gen.decode_id(full.start_id).incrementing_id, 0);
+        assert_eq!(gen.decode_id(full.end_id).incrementing_id, 1023);
+        assert_eq!(full.ids().count(), 1024);
+
+        clock.advance(1);
+        let single = gen.reserve_block(NodeId(3), 1).unwrap();
+        assert_eq!(single.ids().collect::<Vec<_>>(), [single.start_id]);
+        assert!(matches!(
+            gen.reserve_block(NodeId(3), 0),
+            Err(GenError::BlockUnavailable { requested: 0, .. })
+        ));
+        assert!(gen.reserve_block(wide_node(1024), 1).is_err());
+    }
+
+    #[test]
+    fn test_guess_preset() {
+        let presets = [
+            (ConfigPreset::ShardedConfig, 1),
+            (ConfigPreset::ShortEpochMaxNodes, 3),
+            (ConfigPreset::ShardedConfigWide, 4),
+        ];
+        for (preset, config_id) in presets {
+            let gen = IdGenerator::new(preset, DEFAULT_EPOCH);
+            let mut id = gen.next_id(NodeId(7));
+            if gen.supports_sharding() {
+                id = gen.derive_sharded_id(id, 2);
+            }
+            assert_eq!(guess_preset(id).map(|p| p.config_id()), Some(config_id));
+        }
+
+        // A snowflake whose sequence leaves the low bits clear has no config
+        // id match, so it falls through to Snowflake
+        let snowflake = IdConfig::new(ConfigPreset::Snowflake, DEFAULT_EPOCH);
+        let id = snowflake.encode(5_000, 33, 8);
+        assert!(matches!(guess_preset(id), Some(ConfigPreset::Snowflake)));
+
+        // With sequence 1 its low bits read as config id 1, and the sharded
+        // preset wins since nothing in the id rules it out
+        let ambiguous = snowflake.encode(5_000, 33, 1);
+        assert!(matches!(
+            guess_preset(ambiguous),
+            Some(ConfigPreset::ShardedConfig)
+        ));
+
+        // Config id 4 with a time past now isn't a plausible wide id, and the
+        // top bit rules out a snowflake
+        let wide = IdConfig::new(ConfigPreset::ShardedConfigWide, DEFAULT_EPOCH);
+        assert!(guess_preset(wide.encode(u64::MAX, 1, 0)).is_none());
+
+        // Custom config ids aren't known
+        let custom = IdConfig::new(ConfigPreset::Custom(DEFAULT_EPOCH, 41, 10, 0, 10, 2), 0);
+        let id = custom.encode(5_000, 1, 0) | 1 << 63;
+        assert!(guess_preset(id).is_none());
+    }
+
+    #[test]
+    fn test_rollover_strategies() {
+        let strategies = [
+            RolloverStrategy::Spin,
+            RolloverStrategy::YieldNow,
+            RolloverStrategy::SleepUntilNextMs,
+        ];
+        for strategy in strategies {
+            let clock = MockClock::new(DEFAULT_EPOCH + 1000);
+            let mut gen = IdGenerator::builder()
+                .epoch_bits(41)
+                .node_bits(10)
+                .clock(clock.clone())
+                .rollover_strategy(strategy)
+                .build()
+                .unwrap();
+            let spent = gen.next_ids(NodeId(1), 1024);
+
+            // Each wait is recorded, and the clock moves on after the fifth
+            let waits = Arc::new(Mutex::new(Vec::new()));
+            gen.rollover_wait_hook = Some(Arc::new({
+                let (waits, clock) = (waits.clone(), clock.clone());
+                move |strategy| {
+                    let mut waits = waits.lock().unwrap();
+                    waits.push(strategy);
+                    if waits.len() == 5 {
+                        clock.advance(1);
+                    }
+                }
+            }));
+            let id = gen.next_id(NodeId(1));
+            assert_eq!(*waits.lock().unwrap(), [strategy; 5]);
+            assert_eq!(gen.decode_id(id).time, 1001, "{:?}", strategy);
+            assert!(!spent.contains(&id));
+        }
+    }
+
+    #[test]
+    fn test_rebase_epoch_keeps_absolute_time() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 86_400_000);
+        let gen = IdGenerator::with_clock(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 41, 10, 0, 10, 2),
+            DEFAULT_EPOCH,
+            clock.clone(),
+        );
+        let id = gen.next_id(NodeId(77));
+        let decoded = gen.decode_id(id);
+
+        // A year earlier
+        let earlier = DEFAULT_EPOCH - 31_536_000_000;
+        let rebased = gen.rebase_epoch(id, earlier).unwrap();
+        let merged = IdGenerator::new(ConfigPreset::Custom(earlier, 41, 10, 0, 10, 2), earlier);
+        let moved = merged.decode_id(rebased);
+        assert_eq!(
+            moved.timestamp_millis(earlier),
+            decoded.timestamp_millis(DEFAULT_EPOCH)
+        );
+        assert_eq!(moved.time, decoded.time + 31_536_000_000);
+        assert_eq!(
+            (moved.node_id, moved.incrementing_id, moved.config_id),
+            (77, decoded.incrementing_id, 2)
+        );
+        assert_eq!(merged.rebase_epoch(rebased, DEFAULT_EPOCH), Ok(id));
+
+        // After the id's own time the offset would be negative
+        let later = DEFAULT_EPOCH + 86_400_001;
+        assert_eq!(
+            gen.rebase_epoch(id, later),
+            Err(GenError::ClockBehindEpoch {
+                epoch: later,
+                now: DEFAULT_EPOCH + 86_400_000
+            })
+        );
+
+        // 20 time bits can't reach back a whole day
+        let narrow = IdGenerator::with_clock(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 20, 10, 0, 10, 2),
+            DEFAULT_EPOCH,
+            clock.clone(),
+        );
+        clock.set(DEFAULT_EPOCH + 1000);
+        let id = narrow.next_id(NodeId(1));
+        assert!(matches!(
+            narrow.rebase_epoch(id, DEFAULT_EPOCH - 86_400_000),
+            Err(GenError::TimestampOutOfRange { .. })
+        ));
+    }
+
+    #[test]
+    fn test_i64_round_trip() {
+        let gen = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
+        let id = gen.next_id(NodeId(99)) | 1 << 63;
+        let signed = id_to_i64(id);
+        assert!(signed < 0);
+        assert_eq!(id_from_i64(signed), id);
+        assert_eq!(DecodedId::from_i64(signed), Ok(gen.decode_id(id)));
+        assert_eq!(DecodedId::from_i64(signed).unwrap().node_id, 99);
+
+        let low = gen.next_id(NodeId(1)) & !(1 << 63);
+        assert_eq!(id_to_i64(low), low as i64);
+        assert!(id_to_i64(low) >= 0);
+        assert_eq!(id_to_i64(u64::MAX), -1);
+        assert_eq!(id_from_i64(i64::MIN), 1 << 63);
+    }
+
+    #[test]
+    fn test_min_max_id_for_time_bound_generated_ids() {
+        let millis = DEFAULT_EPOCH + 60_000;
+        let clock = MockClock::new(millis);
+        let gen =
+            IdGenerator::with_clock(ConfigPreset::ShardedConfig, DEFAULT_EPOCH, clock.clone());
+        let (min, max) = (
+            gen.min_id_for_time(NodeId(12), millis).unwrap(),
+            gen.max_id_for_time(NodeId(12), millis).unwrap(),
+        );
+        assert_eq!(gen.decode_id(min).time, 60_000);
+        assert_eq!(gen.decode_id(max).shard_id, 31);
+        assert_eq!(gen.decode_id(max).incrementing_id, 1023);
+
+        for id in gen.next_ids(NodeId(12), 1024) {
+            assert!((min..=max).contains(&id));
+            for shard in 0..32 {
+                assert!((min..=max).contains(&gen.derive_sharded_id(id, shard)));
+            }
+        }
+
+        // Neighbouring milliseconds and nodes fall outside
+        clock.advance(1);
+        assert!(gen.next_id(NodeId(12)) > max);
+        assert!(gen.min_id_for_time(NodeId(12), millis + 1).unwrap() > max);
+        assert!(gen.max_id_for_time(NodeId(12), millis - 1).unwrap() < min);
+        assert!(gen.max_id_for_time(NodeId(11), millis).unwrap() < min);
+
+        let checked = IdGenerator::builder()
+            .epoch_bits(28)
+            .node_bits(14)
+            .shard_bits(5)
+            .shard_checksum_bits(4)
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+        let (min, max) = (
+            checked.min_id_for_time(NodeId(3), millis + 1).unwrap(),
+            checked.max_id_for_time(NodeId(3), millis + 1).unwrap(),
+        );
+        assert_eq!(checked.decode_id(max).shard_id, 31);
+        let ids = checked.next_ids(NodeId(3), 1024);
+        for shard in 0..32 {
+            for &id in &ids {
+                let derived = checked.derive_sharded_id(id, shard);
+                assert!((min..=max).contains(&derived), "shard {}", shard);
+            }
+        }
+
+        assert!(matches!(
+            gen.min_id_for_time(NodeId(12), DEFAULT_EPOCH - 1),
+            Err(GenError::ClockBehindEpoch { .. })
+        ));
+        assert!(matches!(
+            gen.max_id_for_time(wide_node(1 << 14), millis),
+            Err(GenError::NodeIdOutOfRange { .. })
+        ));
+    }
+
+    #[test]
+    fn test_decode_many_matches_decode_id() {
+        let gen = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        let mut ids: Vec<u64> = (0..500).map(|i| gen.next_id(NodeId(i % 7))).collect();
+        ids.push(gen.derive_sharded_id(ids[0], 9));
+        let decoded = gen.decode_many(&ids);
+        assert_eq!(decoded.len(), ids.len());
+        for (id, decoded) in ids.iter().zip(&decoded) {
+            assert_eq!(*decoded, gen.decode_id(*id));
+        }
+        assert!(gen.decode_many(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_const_generator_matches_dynamic() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 1_000);
+        let fixed =
+            ConstIdGenerator::<41, 10, 0>::with_clock(DEFAULT_EPOCH, 2, clock.clone()).unwrap();
+        let dynamic = IdGenerator::with_clock(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 41, 10, 0, 10, 2),
+            DEFAULT_EPOCH,
+            clock.clone(),
+        );
+        assert_eq!(IdConfig::from(&dynamic), fixed.config());
+
+        for i in 0..3000u32 {
+            if i % 700 == 0 {
+                clock.advance(1);
+            }
+            let (a, b) = (
+                fixed.next_id(NodeId(i % 1024)),
+                dynamic.next_id(NodeId(i % 1024)),
+            );
+            assert_eq!(a, b);
+            assert_eq!(fixed.decode_id(a), dynamic.decode_id(b));
+        }
+        assert_eq!(
+            fixed.try_next_id(wide_node(1024)),
+            Err(GenError::NodeIdOutOfRange {
+                node_id: 1024,
+                max_nodes: 1024
+            })
+        );
+
+        let sharded = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        let fixed = ConstIdGenerator::<32, 14, 5>::new(DEFAULT_EPOCH, 1).unwrap();
+        let id = sharded.derive_sharded_id(sharded.next_id(NodeId(9000)), 17);
+        assert_eq!(fixed.decode_id(id), sharded.decode_id(id));
+
+        assert_eq!(
+            ConstIdGenerator::<41, 10, 0>::new(DEFAULT_EPOCH, 8).err(),
+            Some(ConfigError::ConfigIdTooLarge {
+                config_id: 8,
+                config_bits: 3
+            })
+        );
+    }
+
+    #[derive(Default)]
+    struct CountingMetrics {
+        generated: AtomicU64,
+        waits: Mutex<Vec<Duration>>,
+    }
+
+    impl Metrics for CountingMetrics {
+        fn on_id_generated(&self) {
+            self.generated.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_rollover_wait(&self, waited: Duration) {
+            self.waits.lock().unwrap().push(waited);
+        }
+    }
+
+    #[test]
+    fn test_metrics_count_ids_and_rollover_waits() {
+        let metrics = Arc::new(CountingMetrics::default());
+        let clock = MockClock::new(DEFAULT_EPOCH + 1000);
+        let gen = Arc::new(
+            IdGenerator::builder()
+                .epoch_bits(41)
+                .node_bits(10)
+                .clock(clock.clone())
+                .metrics(metrics.clone())
+                .build()
+                .unwrap(),
+        );
+
+        for i in 0..1024 {
+            gen.next_id(NodeId(i % 3));
+            assert_eq!(metrics.generated.load(Ordering::SeqCst), i as u64 + 1);
+        }
+        assert!(metrics.waits.lock().unwrap().is_empty());
+
+        // The millisecond is spent, so the next id waits for the clock
+        let reads_before = clock.reads();
+        let handle = thread::spawn({
+            let gen = gen.clone();
+            move || gen.next_id(NodeId(1))
+        });
+        while clock.reads() < reads_before + 100 {
+            thread::yield_now();
+        }
+        clock.advance(2);
+        handle.join().unwrap();
+
+        assert_eq!(metrics.generated.load(Ordering::SeqCst), 1025);
+        assert_eq!(*metrics.waits.lock().unwrap(), [Duration::from_millis(2)]);
+
+        gen.next_ids(NodeId(1), 10);
+        assert_eq!(metrics.generated.load(Ordering::SeqCst), 1035);
+    }
+
+    #[test]
+    fn test_fixed_node_generator() {
+        let gen = IdGenerator::with_fixed_node(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 41, 10, 0, 10, 2),
+            DEFAULT_EPOCH,
+            513,
+        )
+        .unwrap();
+        let ids: Vec<u64> = (0..100).map(|_| gen.next()).collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(ids.iter().all(|&id| gen.decode_id(id).node_id == 513));
+        assert_eq!(gen.decode_id(gen.try_next().unwrap()).node_id, 513);
+
+        assert_eq!(
+            IdGenerator::with_fixed_node(ConfigPreset::Snowflake, DEFAULT_EPOCH, 1024).err(),
+            Some(GenError::NodeIdOutOfRange {
+                node_id: 1024,
+                max_nodes: 1024
+            })
+        );
+    }
+
+    #[test]
+    fn test_next_ids_for_nodes() {
+        let gen = IdGenerator::new(
+            ConfigPreset::Custom(DEFAULT_EPOCH, 37, 14, 0, 10, 2),
+            DEFAULT_EPOCH,
+        );
+        let nodes = [0, 1, 16383].map(|raw| gen.node_id(raw).unwrap());
+        let ids = gen.next_ids_for_nodes(&nodes).unwrap();
+        assert_eq!(ids.len(), nodes.len());
+        for (id, node_id) in ids.iter().zip(nodes) {
+            assert_eq!(gen.decode_id(*id).node_id, node_id.get() as u64);
+        }
+        assert!(gen.next_ids_for_nodes(&[]).unwrap().is_empty());
+
+        let before = gen.current_sequence();
+        assert_eq!(
+            gen.next_ids_for_nodes(&[NodeId(2), wide_node(16384)]),
+            Err(GenError::NodeIdOutOfRange {
+                node_id: 16384,
+                max_nodes: 16384
+            })
+        );
+        assert_eq!(gen.current_sequence(), before);
+    }
+
+    #[test]
+    fn test_decode_is_total_over_unchecked_layouts() {
+        // The fuzz target's seeds, plus random widths well past 64 bits
+        let ids = [
+            0,
+            u64::MAX,
+            0b111,
+            0x3ff << 3,
+            0x1f << 13,
+            0x3fff << 18,
+            u64::MAX << 32,
+            1 << 63,
+        ];
+        let mut rng = rand::thread_rng();
+        for _ in 0..2000 {
+            let widths: [u8; 6] = rng.gen();
+            let config = IdConfig {
+                epoch: DEFAULT_EPOCH,
+                epoch_bits: widths[0],
+                node_bits: widths[1],
+                shard_bits: widths[2],
+                config_id: rng.gen(),
+                config_bits: widths[3],
+                inc_bits: widths[4],
+                shard_checksum_bits: widths[5],
+                shard2_bits: rng.gen(),
+                salt_bits: rng.gen(),
+            };
+            let _ = config.validate().map_err(|err| err.to_string());
+            for id in ids.into_iter().chain([rng.gen()]) {
+                let _ = config.decode(id);
+                let _ = config.verify_shard_checksum(id);
+                let _ = config.derive_sharded_id(id, rng.gen());
+            }
+        }
+    }
+
+    #[test]
+    fn test_next_sharded_id() {
+        let clock = MockClock::new(DEFAULT_EPOCH + 5000);
+        let gen =
+            IdGenerator::with_clock(ConfigPreset::ShardedConfig, DEFAULT_EPOCH, clock.clone());
+        let twin =
+            IdGenerator::with_clock(ConfigPreset::ShardedConfig, DEFAULT_EPOCH, clock.clone());
+
+        for shard in [0, 1, 31] {
+            let id = gen.next_sharded_id(NodeId(9), shard).unwrap();
+            assert_eq!(id, twin.derive_sharded_id(twin.next_id(NodeId(9)), shard));
+            assert_eq!(gen.shard_of(id), shard);
+        }
+
+        let before = gen.current_sequence();
+        assert_eq!(
+            gen.next_sharded_id(NodeId(9), 32),
+            Err(ShardError::ShardOutOfRange { shard: 32, max: 32 })
+        );
+        assert_eq!(gen.current_sequence(), before);
+
+        let unsharded =
+            IdGenerator::with_clock(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH, clock);
+        assert_eq!(
+            unsharded.next_sharded_id(NodeId(9), 0),
+            Err(ShardError::ShardingUnsupported)
+        );
+    }
+
+    #[test]
+    fn test_reshard_only_changes_shard() {
+        let gen = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        let id = gen.derive_sharded_id(gen.next_id(NodeId(9)), 4);
+        let original = gen.decode_id(id);
+        assert_eq!(gen.shard_of(id), 4);
+
+        let mut current = id;
+        for shard in (0..32).rev() {
+            current = gen.reshard(current, shard).unwrap();
+            let decoded = gen.decode_id(current);
+            assert_eq!(gen.shard_of(current), shard);
+            assert_eq!(decoded.shard_id, shard);
+            assert_eq!(decoded.time, original.time);
+            assert_eq!(decoded.node_id, original.node_id);
+            assert_eq!(decoded.incrementing_id, original.incrementing_id);
+            assert_eq!(decoded.config_id, original.config_id);
+        }
+
+        assert_eq!(
+            gen.reshard(id, 32),
+            Err(ShardError::ShardOutOfRange { shard: 32, max: 32 })
+        );
+
+        let short = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
+        assert_eq!(short.shard_of(short.next_id(NodeId(1))), 0);
+        assert_eq!(short.reshard(1, 1), Err(ShardError::ShardingUnsupported));
+    }
+
+    #[test]
+    fn test_sharding_follows_custom_inc_bits() {
+        for inc_bits in [4, 12, 16] {
+            let gen = IdGenerator::builder()
+                .epoch_bits(36)
+                .node_bits(4)
+                .shard_bits(5)
+                .inc_bits(inc_bits)
+                .build()
+                .unwrap();
+            assert_eq!(IdConfig::from(&gen).shard_shift(), 3 + inc_bits);
+            let id = gen.next_id(NodeId(15));
+            for shard in [0, 1, 31] {
+                let sharded = gen.derive_sharded_id(id, shard);
+                let shard_mask = field_mask(5) << (3 + inc_bits);
+                assert_eq!(sharded & !shard_mask, id & !shard_mask);
+                let decoded = gen.decode_id(sharded);
+                assert_eq!((decoded.shard_id, decoded.node_id), (shard, 15));
+                assert_eq!(decoded.incrementing_id, gen.decode_id(id).incrementing_id);
+            }
+        }
+    }
+
+    #[test]
+    fn test_try_derive_sharded_id_errors() {
+        let short = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
+        assert_eq!(
+            short.try_derive_sharded_id(short.next_id(NodeId(1)), 0),
+            Err(ShardError::ShardingUnsupported)
+        );
+
+        let sharded = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
+        let id = sharded.next_id(NodeId(1));
+        assert_eq!(
+            sharded.try_derive_sharded_id(id, 32),
+            Err(ShardError::ShardOutOfRange { shard: 32, max: 32 })
+        );
+        assert_eq!(
+            sharded.try_derive_sharded_id(id, 31),
+            Ok(sharded.derive_sharded_id(id, 31))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "This configuration doesn't support sharding")]
+    fn test_derive_sharded_id_panics_without_shard_bits() {
+        let short = IdGenerator::new(ConfigPreset::ShortEpochMaxNodes, DEFAULT_EPOCH);
+        short.derive_sharded_id(short.next_id(NodeId(1)), 0);
+    }
+
     #[test]
     fn test_sharding_functionality() {
         let gen = IdGenerator::new(ConfigPreset::ShardedConfig, DEFAULT_EPOCH);
 
-        let original_id = gen.next_id(1);
+        let original_id = gen.next_id(NodeId(1));
         let original_decoded = gen.decode_id(original_id);
         println!(
             "Original ID: {}, node_id: {}",